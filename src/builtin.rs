@@ -0,0 +1,131 @@
+use crate::{
+    route::{expand_params, RouteParams},
+    router_service::{full_body, strip_port, ServiceResponse},
+    Error
+};
+use hyper::{header, Response, StatusCode};
+
+
+/// Response defined in config, sent without spawning a handler
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FixedResponse {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: String
+}
+
+impl FixedResponse {
+    pub fn response(&self) -> Result<ServiceResponse, Error> {
+        let mut builder = Response::builder()
+            .status(self.status);
+
+        for (name, value) in &self.headers {
+            builder = builder.header(name, value);
+        }
+
+        builder.body(full_body(self.body.clone()))
+            .map_err(|e| Error::InvalidHeader(e.to_string()))
+    }
+}
+
+/// Redirect to `location`, or to the request path on another host or scheme
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Redirect {
+    /// Target path or URL, may use `${name}` route params
+    pub location: Option<String>,
+    pub status: StatusCode,
+    /// Canonical host to redirect to
+    pub host: Option<String>,
+    /// Redirect to `https`
+    pub https: bool
+}
+
+impl Redirect {
+    pub fn new(location: Option<String>) -> Self {
+        Redirect { location, status: StatusCode::MOVED_PERMANENTLY, host: None, https: false }
+    }
+
+    /// Target of redirect, relative targets become absolute when the host or
+    /// scheme changes. `authority` is the request host with its port, `tls`
+    /// is whether the request came in over TLS. `None` when the redirect
+    /// would lead back to the request, e.g. to HTTPS over TLS.
+    pub fn location(
+        &self,
+        params: &RouteParams<'_>,
+        authority: Option<&str>,
+        path_and_query: &str,
+        tls: bool
+    ) -> Option<String> {
+        let target = match &self.location {
+            Some(location) => expand_params(location, params),
+            None => path_and_query.to_string()
+        };
+
+        if !target.starts_with('/') || target.starts_with("//") || (self.host.is_none() && !self.https) {
+            return Some(target);
+        }
+
+        // the port only carries over to the same scheme
+        let same_scheme = !self.https || tls;
+        let port = authority.map_or("", |a| &a[strip_port(a).len()..]);
+        let host = match (&self.host, authority) {
+            (Some(host), _) if same_scheme && strip_port(host) == host => format!("{host}{port}"),
+            (Some(host), _) => host.clone(),
+            (None, Some(authority)) if same_scheme => authority.to_string(),
+            (None, Some(authority)) => strip_port(authority).to_string(),
+            (None, None) => return (self.location.is_some() || !same_scheme).then_some(target)
+        };
+
+        if self.location.is_none() && same_scheme && authority.is_some_and(|a| a.eq_ignore_ascii_case(&host)) {
+            return None;
+        }
+
+        let scheme = if self.https || tls { "https" } else { "http" };
+        Some(format!("{scheme}://{host}{target}"))
+    }
+
+    pub fn response(&self, location: &str) -> Result<ServiceResponse, Error> {
+        Response::builder()
+            .status(self.status)
+            .header(header::LOCATION, location)
+            .body(full_body(""))
+            .map_err(|e| Error::InvalidHeader(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redirect_location() {
+        let name = String::from("x");
+        let params = vec![(&name, String::from("a/b"))];
+        let location = |redirect: &Redirect, authority: &str, path: &str, tls: bool| {
+            redirect.location(&params, Some(authority), path, tls)
+        };
+
+        let redirect = Redirect::new(Some("/new/${x}".to_string()));
+        assert_eq!(location(&redirect, "example.test", "/old/a/b", false).unwrap(), "/new/a/b");
+
+        let redirect = Redirect { https: true, ..Redirect::new(None) };
+        assert_eq!(location(&redirect, "example.test", "/old?q=1", false).unwrap(), "https://example.test/old?q=1");
+        // the port of the HTTP listener isn't the HTTPS one
+        assert_eq!(location(&redirect, "example.test:8080", "/", false).unwrap(), "https://example.test/");
+        // already on HTTPS
+        assert_eq!(location(&redirect, "example.test", "/old", true), None);
+        assert_eq!(redirect.location(&params, None, "/old", true), None);
+
+        let redirect = Redirect { host: Some("www.example.test".to_string()), ..Redirect::new(None) };
+        assert_eq!(location(&redirect, "example.test", "/", false).unwrap(), "http://www.example.test/");
+        assert_eq!(location(&redirect, "example.test", "/", true).unwrap(), "https://www.example.test/");
+        assert_eq!(location(&redirect, "example.test:8080", "/", false).unwrap(), "http://www.example.test:8080/");
+        assert_eq!(location(&redirect, "www.example.test", "/", false), None);
+
+        let redirect = Redirect { host: Some("www.example.test:8443".to_string()), https: true, ..Redirect::new(None) };
+        assert_eq!(location(&redirect, "example.test:8080", "/", false).unwrap(), "https://www.example.test:8443/");
+
+        let redirect = Redirect { https: true, ..Redirect::new(Some("https://other.test/${x}".to_string())) };
+        assert_eq!(location(&redirect, "example.test", "/", false).unwrap(), "https://other.test/a/b");
+    }
+}
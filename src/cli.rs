@@ -1,7 +1,24 @@
+use crate::{
+    listener::{group_id, user_id, ListenAddr, ListenerConfig, SocketFile},
+    server::{ConnectionLimits, OverLimit}
+};
 pub use clap::Parser;
 use serde::{Deserialize, Deserializer};
-use shell_serve::route::Route;
-use std::{fs, io, net::IpAddr, path::{Path, PathBuf}};
+use hyper::StatusCode;
+use shell_serve::{
+    builtin::{FixedResponse, Redirect},
+    error_page::{ErrorPage, ErrorPages},
+    proxy::{ForwardedHeader, IpNet},
+    rewrite::{HeaderCondition, RewriteRule},
+    route::{Route, RouteAction, RouteOptions, TrailingSlash},
+    static_files::StaticDir,
+    tls::{CertPair, ClientAuth, ClientCa}
+};
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}, time::Duration};
+
+
+/// Smallest `max_header_size`, hyper's minimum read buffer
+const MIN_HEADER_SIZE: usize = 8192;
 
 
 #[derive(Parser)]
@@ -11,14 +28,136 @@ pub struct Cli {
     #[arg(short, long)]
     pub file: Option<PathBuf>,
 
+    /// IP address, or `unix:` path of socket to listen on
     #[arg(short, long, default_value = "127.0.0.1")]
-    pub listen: IpAddr,
+    pub listen: ListenAddr,
 
     #[arg(short, long, default_value = "8000")]
     pub port: u16,
 
+    /// Permissions of the Unix socket, in octal, e.g. 660
+    #[arg(long, value_parser = parse_mode)]
+    pub socket_mode: Option<u32>,
+
+    /// Owner of the Unix socket, as `user[:group]`
+    #[arg(long)]
+    pub socket_owner: Option<String>,
+
+    /// Include internal error details in error responses
+    #[arg(long)]
+    pub debug: bool,
+
+    /// Maximum request body size in bytes
+    #[arg(long)]
+    pub max_body_size: Option<u64>,
+
+    /// Directory for request body temp files
+    #[arg(long)]
+    pub temp_dir: Option<PathBuf>,
+
+    /// Host to route requests as, when the request host matches no route host
+    #[arg(long)]
+    pub default_host: Option<String>,
+
+    /// Exit after this many seconds without open connections, e.g. when
+    /// started by socket activation
+    #[arg(long)]
+    pub idle_timeout: Option<u64>,
+
+    /// Reload routes when the config file changes, as on SIGHUP
+    #[arg(long)]
+    pub watch_config: bool,
+
+    /// Seconds to wait on shutdown for open connections and running handlers,
+    /// before handlers are terminated
+    #[arg(long, default_value = "30")]
+    pub drain_timeout: u64,
+
+    /// Seconds for handlers to start their response, before they're killed
+    /// and a 504 is sent
+    #[arg(long)]
+    pub handler_timeout: Option<u64>,
+
+    /// Seconds for clients to finish the TLS handshake, and to send request
+    /// headers
+    #[arg(long, default_value = "30")]
+    pub header_timeout: u64,
+
+    /// Seconds to keep connections open without requests
+    #[arg(long, default_value = "60")]
+    pub keepalive_timeout: u64,
+
+    /// Maximum size of request headers in bytes, at least 8192
+    #[arg(long, value_parser = parse_header_size)]
+    pub max_header_size: Option<usize>,
+
+    /// Maximum number of open connections, across all listeners
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+
+    /// Connections over `--max-connections`: queue or reject
+    #[arg(long, default_value = "queue")]
+    pub over_limit: OverLimit,
+
+    /// Accept HTTP/2 without TLS, from clients with prior knowledge (h2c)
+    #[arg(long)]
+    pub h2c: bool,
+
+    /// Also serve HTTP/3 over QUIC, on the same UDP port, needs TLS
+    #[arg(long)]
+    pub http3: bool,
+
+    /// Expect a PROXY protocol v1 or v2 header at the start of connections
+    #[arg(long)]
+    pub proxy_protocol: bool,
+
+    /// Networks of proxies whose forwarded client addresses are used, e.g.
+    /// `10.0.0.0/8,::1`
+    #[arg(long, value_delimiter = ',')]
+    pub trusted_proxies: Vec<IpNet>,
+
+    /// Header trusted proxies set to the client address: x-forwarded-for or
+    /// forwarded
+    #[arg(long, default_value = "x-forwarded-for")]
+    pub forwarded_header: ForwardedHeader,
+
+    /// Handling of trailing and repeated slashes: ignore, strict or redirect
+    #[arg(long, default_value = "ignore")]
+    pub trailing_slash: TrailingSlash,
+
+    /// TLS certificate chain PEM file, repeat along with `--tls-key` for
+    /// more certificates picked by SNI name
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Vec<PathBuf>,
+
+    /// TLS private key PEM file, of the `--tls-cert` at the same position
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Vec<PathBuf>,
+
+    /// CA bundle PEM file to verify client certificates against, needs
+    /// `--tls-cert` here or in the config file
+    #[arg(long)]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Whether clients must have a certificate: require or optional
+    #[arg(long, default_value = "require")]
+    pub tls_client_auth: ClientAuth,
+
     #[arg(required_unless_present = "file", num_args = 1..)]
-    pub routes: Vec<Route>
+    pub routes: Vec<Route>,
+
+    /// Error pages, only configurable from file
+    #[arg(skip)]
+    pub error_pages: ErrorPages,
+
+    /// Rewrite rules, only configurable from file
+    #[arg(skip)]
+    pub rewrites: Vec<RewriteRule>,
+
+    /// Listeners, only configurable from file, replacing the top level
+    /// listen and TLS options
+    #[arg(skip)]
+    pub listeners: Vec<ListenerConfig>
 }
 
 impl Cli {
@@ -29,58 +168,667 @@ impl Cli {
         let config: ConfigFile = toml::from_str(&toml_src)?;
 
         if let Some(listen) = config.listen {
-            self.listen = listen;
+            self.listen = listen.parse()?;
         }
 
         if let Some(port) = config.port {
             self.port = port;
         }
 
+        if let Some(mode) = config.socket_mode {
+            self.socket_mode = Some(parse_mode(&mode).map_err(ConfigError::InvalidSocket)?);
+        }
+
+        if config.socket_owner.is_some() {
+            self.socket_owner = config.socket_owner;
+        }
+
+        if let Some(debug) = config.debug {
+            self.debug = debug;
+        }
+
+        if config.max_body_size.is_some() {
+            self.max_body_size = config.max_body_size;
+        }
+
+        if config.temp_dir.is_some() {
+            self.temp_dir = config.temp_dir;
+        }
+
+        if config.default_host.is_some() {
+            self.default_host = config.default_host;
+        }
+
+        if config.idle_timeout.is_some() {
+            self.idle_timeout = config.idle_timeout;
+        }
+
+        if let Some(watch_config) = config.watch_config {
+            self.watch_config = watch_config;
+        }
+
+        if let Some(drain_timeout) = config.drain_timeout {
+            self.drain_timeout = drain_timeout;
+        }
+
+        if config.handler_timeout.is_some() {
+            self.handler_timeout = config.handler_timeout;
+        }
+
+        if let Some(header_timeout) = config.header_timeout {
+            self.header_timeout = header_timeout;
+        }
+
+        if let Some(keepalive_timeout) = config.keepalive_timeout {
+            self.keepalive_timeout = keepalive_timeout;
+        }
+
+        if let Some(size) = config.max_header_size {
+            self.max_header_size = Some(check_header_size(size).map_err(ConfigError::InvalidLimit)?);
+        }
+
+        if config.max_connections.is_some() {
+            self.max_connections = config.max_connections;
+        }
+
+        if let Some(over_limit) = config.over_limit {
+            self.over_limit = over_limit.parse().map_err(ConfigError::InvalidLimit)?;
+        }
+
+        if let Some(h2c) = config.h2c {
+            self.h2c = h2c;
+        }
+
+        if let Some(http3) = config.http3 {
+            self.http3 = http3;
+        }
+
+        if let Some(proxy_protocol) = config.proxy_protocol {
+            self.proxy_protocol = proxy_protocol;
+        }
+
+        if let Some(proxies) = config.trusted_proxies {
+            self.trusted_proxies = Vec::from(proxies).iter()
+                .map(|net| net.parse())
+                .collect::<Result<_, _>>()
+                .map_err(ConfigError::TrustedProxies)?;
+        }
+
+        if let Some(header) = config.forwarded_header {
+            self.forwarded_header = header.parse().map_err(ConfigError::InvalidForwardedHeader)?;
+        }
+
+        if let Some(trailing_slash) = config.trailing_slash {
+            self.trailing_slash = trailing_slash.parse()?;
+        }
+
+        if let Some(tls_cert) = config.tls_cert {
+            self.tls_cert = Vec::from(tls_cert).into_iter().map(PathBuf::from).collect();
+        }
+
+        if let Some(tls_key) = config.tls_key {
+            self.tls_key = Vec::from(tls_key).into_iter().map(PathBuf::from).collect();
+        }
+
+        if config.tls_client_ca.is_some() {
+            self.tls_client_ca = config.tls_client_ca;
+        }
+
+        if let Some(client_auth) = config.tls_client_auth {
+            self.tls_client_auth = parse_client_auth(&client_auth)?;
+        }
+
+        // host specific routes are matched before routes for any host
+        if let Some(routes) = config.hosts {
+            self.routes.extend(routes);
+        }
+
         if let Some(routes) = config.routes {
             self.routes.extend(routes);
         }
 
+        if let Some(error_pages) = config.error_handlers {
+            self.error_pages = error_pages;
+        }
+
+        if let Some(rewrites) = config.rewrites {
+            self.rewrites = rewrites;
+        }
+
+        if let Some(listeners) = config.listeners {
+            self.listeners = listeners.into_iter()
+                .map(ListenerConfig::try_from)
+                .collect::<Result<_, _>>()?;
+        }
+
         Ok(self)
     }
+
+    /// Listeners from the config file, or the one set by the top level options
+    pub fn listeners(&self) -> Result<Vec<ListenerConfig>, ConfigError> {
+        if !self.listeners.is_empty() {
+            return Ok(self.listeners.clone());
+        }
+
+        let tls_certs = cert_pairs(&self.tls_cert, &self.tls_key)?;
+
+        Ok(vec![ListenerConfig {
+            listen: self.listen.clone(),
+            port: self.port,
+            socket_file: socket_file(self.socket_mode, self.socket_owner.as_deref())?,
+            tls_client_ca: client_ca(self.tls_client_ca.clone(), self.tls_client_auth, &tls_certs)?,
+            tls_certs,
+            h2c: self.h2c,
+            http3: self.http3,
+            proxy_protocol: self.proxy_protocol,
+            tags: None,
+            fd_name: None
+        }])
+    }
+
+    pub fn connection_limits(&self) -> ConnectionLimits {
+        ConnectionLimits {
+            header_timeout: Duration::from_secs(self.header_timeout),
+            keepalive_timeout: Duration::from_secs(self.keepalive_timeout),
+            max_header_size: self.max_header_size,
+            max_connections: self.max_connections,
+            over_limit: self.over_limit
+        }
+    }
+}
+
+/// Certificate and key pairs, the first one is the default for clients
+/// without a matching SNI name
+fn cert_pairs(certs: &[PathBuf], keys: &[PathBuf]) -> Result<Vec<CertPair>, ConfigError> {
+    if certs.len() != keys.len() {
+        return Err(ConfigError::TlsKeyMismatch(certs.len(), keys.len()));
+    }
+
+    Ok(certs.iter().zip(keys)
+        .map(|(cert, key)| CertPair { cert: cert.clone(), key: key.clone() })
+        .collect())
+}
+
+/// Client CA of a listener, which only verifies clients over TLS
+fn client_ca(bundle: Option<PathBuf>, auth: ClientAuth, certs: &[CertPair]) -> Result<Option<ClientCa>, ConfigError> {
+    match bundle {
+        Some(_) if certs.is_empty() => Err(ConfigError::ClientCaWithoutCert),
+        bundle => Ok(bundle.map(|bundle| ClientCa { bundle, auth }))
+    }
+}
+
+fn parse_client_auth(auth: &str) -> Result<ClientAuth, ConfigError> {
+    auth.parse()
+        .map_err(|_| ConfigError::InvalidClientAuth(auth.to_string()))
+}
+
+fn socket_file(mode: Option<u32>, owner: Option<&str>) -> Result<SocketFile, ConfigError> {
+    let mut socket_file = SocketFile { mode, ..Default::default() };
+
+    if let Some(owner) = owner {
+        socket_file.set_owner(owner).map_err(ConfigError::InvalidSocket)?;
+    }
+
+    Ok(socket_file)
 }
 
 #[derive(Deserialize)]
 struct ConfigFile {
-    listen: Option<IpAddr>,
+    listen: Option<String>,
     port: Option<u16>,
+    socket_mode: Option<String>,
+    socket_owner: Option<String>,
+    debug: Option<bool>,
+    max_body_size: Option<u64>,
+    temp_dir: Option<PathBuf>,
+    default_host: Option<String>,
+    idle_timeout: Option<u64>,
+    watch_config: Option<bool>,
+    drain_timeout: Option<u64>,
+    handler_timeout: Option<u64>,
+    header_timeout: Option<u64>,
+    keepalive_timeout: Option<u64>,
+    max_header_size: Option<usize>,
+    max_connections: Option<usize>,
+    over_limit: Option<String>,
+    h2c: Option<bool>,
+    http3: Option<bool>,
+    proxy_protocol: Option<bool>,
+    trusted_proxies: Option<StringOrList>,
+    forwarded_header: Option<String>,
+    trailing_slash: Option<String>,
+    tls_cert: Option<StringOrList>,
+    tls_key: Option<StringOrList>,
+    tls_client_ca: Option<PathBuf>,
+    tls_client_auth: Option<String>,
+    #[serde(default, deserialize_with = "config_file_hosts")]
+    hosts: Option<Vec<Route>>,
     #[serde(default, deserialize_with = "config_file_routes")]
-    routes: Option<Vec<Route>>
+    routes: Option<Vec<Route>>,
+    #[serde(default, deserialize_with = "config_file_error_handlers")]
+    error_handlers: Option<ErrorPages>,
+    #[serde(default, deserialize_with = "config_file_rewrites")]
+    rewrites: Option<Vec<RewriteRule>>,
+    listeners: Option<Vec<ConfigListener>>
+}
+
+#[derive(Deserialize)]
+struct ConfigListener {
+    listen: String,
+    port: Option<u16>,
+    socket_mode: Option<String>,
+    socket_owner: Option<String>,
+    h2c: Option<bool>,
+    http3: Option<bool>,
+    proxy_protocol: Option<bool>,
+    tls_cert: Option<StringOrList>,
+    tls_key: Option<StringOrList>,
+    tls_client_ca: Option<PathBuf>,
+    tls_client_auth: Option<String>,
+    /// Tags of the routes served, all routes when unset
+    tags: Option<StringOrList>,
+    fd_name: Option<String>
+}
+
+impl TryFrom<ConfigListener> for ListenerConfig {
+    type Error = ConfigError;
+
+    fn try_from(config: ConfigListener) -> Result<Self, Self::Error> {
+        let paths = |list: Option<StringOrList>| -> Vec<PathBuf> {
+            list.map(Vec::from).unwrap_or_default().into_iter().map(PathBuf::from).collect()
+        };
+        let mode = config.socket_mode.as_deref()
+            .map(parse_mode)
+            .transpose()
+            .map_err(ConfigError::InvalidSocket)?;
+        let client_auth = config.tls_client_auth.as_deref()
+            .map(parse_client_auth)
+            .transpose()?
+            .unwrap_or_default();
+        let tls_certs = cert_pairs(&paths(config.tls_cert), &paths(config.tls_key))?;
+
+        Ok(ListenerConfig {
+            listen: config.listen.parse()?,
+            port: config.port.unwrap_or(8000),
+            socket_file: socket_file(mode, config.socket_owner.as_deref())?,
+            tls_client_ca: client_ca(config.tls_client_ca, client_auth, &tls_certs)?,
+            tls_certs,
+            h2c: config.h2c.unwrap_or_default(),
+            http3: config.http3.unwrap_or_default(),
+            proxy_protocol: config.proxy_protocol.unwrap_or_default(),
+            tags: config.tags.map(Vec::from),
+            fd_name: config.fd_name
+        })
+    }
 }
 
 #[derive(Deserialize)]
 #[serde(untagged)]
 enum ConfigRoute {
     String(String),
-    Object { method: String, path: String, handler: String }
+    Object {
+        method: String,
+        host: Option<String>,
+        path: String,
+        #[serde(flatten)]
+        action: Box<ConfigRouteAction>,
+        #[serde(flatten)]
+        options: Box<ConfigRouteOptions>
+    }
+}
+
+/// What a route does, exactly one of the fields except `index` and `listing`
+/// must be set
+#[derive(Deserialize)]
+struct ConfigRouteAction {
+    handler: Option<String>,
+    #[serde(rename = "static")]
+    static_dir: Option<String>,
+    /// Index files of static route, defaults to `index.html`
+    index: Option<StringOrList>,
+    /// Render directory listings in static route
+    listing: Option<bool>,
+    respond: Option<ConfigRespond>,
+    redirect: Option<ConfigRedirect>,
+    rewrite: Option<String>
 }
 
-impl ToString for ConfigRoute {
-    fn to_string(&self) -> String {
-        match self {
-            ConfigRoute::String(s) => s.to_owned(),
-            ConfigRoute::Object { method, path, handler } =>
-                format!("{method}:{path} {handler}")
+impl TryFrom<ConfigRouteAction> for RouteAction {
+    type Error = shell_serve::Error;
+
+    fn try_from(config: ConfigRouteAction) -> Result<Self, Self::Error> {
+        let mut actions = vec![];
+
+        if let Some(handler) = config.handler {
+            actions.push(RouteAction::Handler(handler));
+        }
+
+        if let Some(root) = config.static_dir {
+            let mut dir = StaticDir::new(root);
+            if let Some(index) = config.index {
+                dir.index = index.into();
+            }
+            dir.listing = config.listing.unwrap_or_default();
+            actions.push(RouteAction::Static(dir));
+        }
+
+        if let Some(respond) = config.respond {
+            actions.push(RouteAction::Respond(respond.try_into()?));
+        }
+
+        if let Some(redirect) = config.redirect {
+            actions.push(RouteAction::Redirect(redirect.try_into()?));
+        }
+
+        if let Some(target) = config.rewrite {
+            actions.push(RouteAction::Rewrite(target));
+        }
+
+        match actions.pop() {
+            Some(action) if actions.is_empty() => Ok(action),
+            _ => Err(shell_serve::Error::InvalidRoute(
+                "route needs one of 'handler', 'static', 'respond', 'redirect' or 'rewrite'".to_string()
+            ))
         }
     }
 }
 
+#[derive(Deserialize)]
+struct ConfigHost {
+    host: String,
+    /// Tags added to all routes of the host
+    tags: Option<StringOrList>,
+    routes: Vec<ConfigRoute>
+}
+
+#[derive(Deserialize)]
+struct ConfigRouteOptions {
+    max_body_size: Option<u64>,
+    body: Option<String>,
+    max_parts: Option<usize>,
+    max_part_size: Option<u64>,
+    accept: Option<StringOrList>,
+    content_type: Option<StringOrList>,
+    sendfile: Option<StringOrList>,
+    may_pass: Option<bool>,
+    stream: Option<bool>,
+    trailing_slash: Option<String>,
+    client_cert: Option<StringOrList>,
+    peer_user: Option<StringOrList>,
+    peer_group: Option<StringOrList>,
+    client_ip: Option<StringOrList>,
+    tags: Option<StringOrList>
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrList {
+    String(String),
+    List(Vec<String>)
+}
+
+impl From<StringOrList> for Vec<String> {
+    fn from(value: StringOrList) -> Self {
+        match value {
+            StringOrList::String(s) => vec![s],
+            StringOrList::List(l) => l
+        }
+    }
+}
+
+impl TryFrom<ConfigRouteOptions> for RouteOptions {
+    type Error = shell_serve::Error;
+
+    fn try_from(options: ConfigRouteOptions) -> Result<Self, Self::Error> {
+        Ok(RouteOptions {
+            max_body_size: options.max_body_size,
+            body: options.body.as_deref().map(str::parse).transpose()?.unwrap_or_default(),
+            max_parts: options.max_parts,
+            max_part_size: options.max_part_size,
+            accept: options.accept.map(Vec::from),
+            content_type: options.content_type.map(Vec::from),
+            sendfile: options.sendfile
+                .map(|dirs| Vec::from(dirs).into_iter().map(PathBuf::from).collect()),
+            may_pass: options.may_pass.unwrap_or_default(),
+            stream: options.stream.unwrap_or_default(),
+            trailing_slash: options.trailing_slash.as_deref().map(str::parse).transpose()?,
+            client_cert: options.client_cert
+                .map(|conditions| Vec::from(conditions).iter().map(|c| c.parse()).collect())
+                .transpose()?,
+            peer_uid: options.peer_user
+                .map(|users| Vec::from(users).iter().map(|u| user_id(u)).collect())
+                .transpose()
+                .map_err(shell_serve::Error::InvalidRoute)?,
+            peer_gid: options.peer_group
+                .map(|groups| Vec::from(groups).iter().map(|g| group_id(g)).collect())
+                .transpose()
+                .map_err(shell_serve::Error::InvalidRoute)?,
+            client_ip: options.client_ip
+                .map(|nets| Vec::from(nets).iter().map(|net| net.parse()).collect())
+                .transpose()?,
+            tags: options.tags.map(Vec::from).unwrap_or_default()
+        })
+    }
+}
+
+impl TryFrom<ConfigRoute> for Route {
+    type Error = shell_serve::Error;
+
+    fn try_from(route: ConfigRoute) -> Result<Self, Self::Error> {
+        match route {
+            ConfigRoute::String(s) => s.parse(),
+            ConfigRoute::Object { method, host, path, action, options } => {
+                let action = (*action).try_into()?;
+
+                let host = host.map(|h| format!("//{h}")).unwrap_or_default();
+                let route = Route::new(&format!("{method}:{host}{path}"), action)?;
+                Ok(route.with_options((*options).try_into()?))
+            }
+        }
+    }
+}
+
+/// Fixed response, either just the body or an object
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigRespond {
+    Body(String),
+    Object {
+        status: Option<u16>,
+        headers: Option<HashMap<String, String>>,
+        body: Option<String>
+    }
+}
+
+impl TryFrom<ConfigRespond> for FixedResponse {
+    type Error = shell_serve::Error;
+
+    fn try_from(respond: ConfigRespond) -> Result<Self, Self::Error> {
+        match respond {
+            ConfigRespond::Body(body) => Ok(FixedResponse { status: StatusCode::OK, headers: vec![], body }),
+            ConfigRespond::Object { status, headers, body } => Ok(FixedResponse {
+                status: config_status(status, StatusCode::OK)?,
+                headers: headers.unwrap_or_default().into_iter().collect(),
+                body: body.unwrap_or_default()
+            })
+        }
+    }
+}
+
+/// Redirect, either just the location or an object
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigRedirect {
+    Location(String),
+    Object {
+        location: Option<String>,
+        status: Option<u16>,
+        host: Option<String>,
+        https: Option<bool>
+    }
+}
+
+impl TryFrom<ConfigRedirect> for Redirect {
+    type Error = shell_serve::Error;
+
+    fn try_from(redirect: ConfigRedirect) -> Result<Self, Self::Error> {
+        match redirect {
+            ConfigRedirect::Location(location) => Ok(Redirect::new(Some(location))),
+            ConfigRedirect::Object { location, status, host, https } => Ok(Redirect {
+                status: config_status(status, StatusCode::MOVED_PERMANENTLY)?,
+                host,
+                https: https.unwrap_or_default(),
+                ..Redirect::new(location)
+            })
+        }
+    }
+}
+
+/// Parse octal file mode, e.g. `660` or `0o660`
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    let digits = mode.strip_prefix("0o").unwrap_or(mode);
+    u32::from_str_radix(digits, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("invalid file mode '{mode}'"))
+}
+
+fn parse_header_size(size: &str) -> Result<usize, String> {
+    let size = size.parse().map_err(|_| format!("invalid size '{size}'"))?;
+    check_header_size(size)
+}
+
+fn check_header_size(size: usize) -> Result<usize, String> {
+    if size < MIN_HEADER_SIZE {
+        return Err(format!("max header size must be at least {MIN_HEADER_SIZE} bytes"));
+    }
+    Ok(size)
+}
+
+fn config_status(status: Option<u16>, default: StatusCode) -> Result<StatusCode, shell_serve::Error> {
+    match status {
+        Some(status) => StatusCode::from_u16(status)
+            .map_err(|_| shell_serve::Error::InvalidStatus(status.to_string())),
+        None => Ok(default)
+    }
+}
+
 fn config_file_routes<'de, D>(deserializer: D) -> Result<Option<Vec<Route>>, D::Error>
     where D: Deserializer<'de>
 {
     let routes: Vec<ConfigRoute> = Deserialize::deserialize(deserializer)?;
-    let routes = routes.iter()
-        .map(|s| s.to_string().parse::<Route>())
+    let routes = routes.into_iter()
+        .map(Route::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(serde::de::Error::custom)?;
+
+    Ok(Some(routes))
+}
+
+fn config_file_hosts<'de, D>(deserializer: D) -> Result<Option<Vec<Route>>, D::Error>
+    where D: Deserializer<'de>
+{
+    let hosts: Vec<ConfigHost> = Deserialize::deserialize(deserializer)?;
+    let routes = hosts.into_iter()
+        .flat_map(|ConfigHost { host, tags, routes }| {
+            let tags = tags.map(Vec::from).unwrap_or_default();
+            routes.into_iter()
+                .map(move |r| {
+                    let route = Route::try_from(r)?.with_host(&host)?;
+                    Ok::<_, shell_serve::Error>(route.with_tags(&tags))
+                })
+        })
         .collect::<Result<Vec<_>, _>>()
         .map_err(serde::de::Error::custom)?;
 
     Ok(Some(routes))
 }
 
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigErrorPage {
+    Handler(String),
+    Object { handler: String },
+    File { file: PathBuf, content_type: Option<String> }
+}
+
+impl From<ConfigErrorPage> for ErrorPage {
+    fn from(page: ConfigErrorPage) -> Self {
+        match page {
+            ConfigErrorPage::Handler(handler) | ConfigErrorPage::Object { handler } =>
+                ErrorPage::Handler(handler),
+            ConfigErrorPage::File { file, content_type } =>
+                ErrorPage::File { path: file, content_type }
+        }
+    }
+}
+
+fn config_file_error_handlers<'de, D>(deserializer: D) -> Result<Option<ErrorPages>, D::Error>
+    where D: Deserializer<'de>
+{
+    let pages: HashMap<String, ConfigErrorPage> = Deserialize::deserialize(deserializer)?;
+    let pages = pages.into_iter()
+        .map(|(status, page)| Ok((status.parse()?, page.into())))
+        .collect::<Result<Vec<_>, shell_serve::Error>>()
+        .map_err(serde::de::Error::custom)?;
+
+    Ok(Some(ErrorPages::new(pages)))
+}
+
+#[derive(Deserialize)]
+struct ConfigRewrite {
+    pattern: String,
+    target: String,
+    method: Option<String>,
+    /// Regex on the raw query string, negated with a `!` prefix
+    query: Option<String>,
+    /// Header name to regex, negated with a `!` prefix
+    headers: Option<HashMap<String, String>>,
+    last: Option<bool>,
+    next: Option<bool>,
+    redirect: Option<u16>
+}
+
+impl TryFrom<ConfigRewrite> for RewriteRule {
+    type Error = shell_serve::Error;
+
+    fn try_from(config: ConfigRewrite) -> Result<Self, Self::Error> {
+        let mut rule = RewriteRule::new(&config.pattern, config.target)?;
+
+        if let Some(method) = config.method {
+            rule = rule.with_method(method.parse()?);
+        }
+
+        if let Some(query) = config.query {
+            rule = rule.with_query(&query)?;
+        }
+
+        for (name, pattern) in config.headers.unwrap_or_default() {
+            rule = rule.with_condition(HeaderCondition::new(&name, &pattern)?);
+        }
+
+        rule.last = config.last.unwrap_or_default();
+        rule.next = config.next.unwrap_or_default();
+        rule.redirect = config.redirect
+            .map(|s| StatusCode::from_u16(s).map_err(|_| shell_serve::Error::InvalidStatus(s.to_string())))
+            .transpose()?;
+
+        Ok(rule)
+    }
+}
+
+fn config_file_rewrites<'de, D>(deserializer: D) -> Result<Option<Vec<RewriteRule>>, D::Error>
+    where D: Deserializer<'de>
+{
+    let rules: Vec<ConfigRewrite> = Deserialize::deserialize(deserializer)?;
+    let rules = rules.into_iter()
+        .map(RewriteRule::try_from)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(serde::de::Error::custom)?;
+
+    Ok(Some(rules))
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum ConfigError {
     #[error("Failed to read config file")]
@@ -93,5 +841,110 @@ pub enum ConfigError {
     InvalidIpAddr(#[from] std::net::AddrParseError),
 
     #[error("Route parse error")]
-    RouteParse(#[from] shell_serve::Error)
-}
\ No newline at end of file
+    RouteParse(#[from] shell_serve::Error),
+
+    #[error("Invalid Unix socket option: {0}")]
+    InvalidSocket(String),
+
+    #[error("Invalid 'trusted_proxies'")]
+    TrustedProxies(#[source] shell_serve::Error),
+
+    #[error("Invalid 'forwarded_header': {0}")]
+    InvalidForwardedHeader(String),
+
+    #[error("Invalid connection limit: {0}")]
+    InvalidLimit(String),
+
+    #[error("Got {0} TLS certificates but {1} keys")]
+    TlsKeyMismatch(usize, usize),
+
+    #[error("Invalid 'tls_client_auth' '{0}', expected require or optional")]
+    InvalidClientAuth(String),
+
+    #[error("'tls_client_ca' needs 'tls_cert'")]
+    ClientCaWithoutCert
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("660"), Ok(0o660));
+        assert_eq!(parse_mode("0660"), Ok(0o660));
+        assert_eq!(parse_mode("0o600"), Ok(0o600));
+        assert_eq!(parse_mode("7777"), Ok(0o7777));
+        assert!(parse_mode("10000").is_err());
+        assert!(parse_mode("668").is_err());
+        assert!(parse_mode("rw").is_err());
+    }
+
+    /// Cli with options and routes from config file `toml`
+    fn load(toml: &str) -> Cli {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(&path, toml).unwrap();
+
+        Cli::try_parse_from(["shell-serve", "--file", path.to_str().unwrap()]).unwrap()
+            .load_config(&path)
+            .unwrap()
+    }
+
+    /// Handlers of the routes each listener serves
+    fn served(cli: &Cli) -> Vec<Vec<String>> {
+        cli.listeners().unwrap().iter()
+            .map(|listener| cli.routes.iter()
+                .filter(|route| listener.serves(route))
+                .filter_map(|route| match route.action() {
+                    RouteAction::Handler(handler) => Some(handler.clone()),
+                    _ => None
+                })
+                .collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_listener_tags() {
+        let cli = load(r#"
+            routes = [
+              { method = "GET", path = "/", handler = "public.sh", tags = "public" },
+              { method = "GET", path = "/both", handler = "both.sh", tags = ["public", "internal"] },
+              "POST:/admin admin.sh"
+            ]
+
+            [[hosts]]
+            host = "api.local"
+            tags = "api"
+            routes = ["GET:/ api.sh", { method = "GET", path = "/status", handler = "status.sh", tags = "public" }]
+
+            [[listeners]]
+            listen = "0.0.0.0"
+            port = 80
+            tags = "public"
+
+            [[listeners]]
+            listen = "127.0.0.1"
+            port = 81
+            tags = ["api", "internal"]
+
+            [[listeners]]
+            listen = "unix:/run/shell-serve.sock"
+        "#);
+
+        assert_eq!(served(&cli), vec![
+            vec!["status.sh", "public.sh", "both.sh"],
+            vec!["api.sh", "status.sh", "both.sh"],
+            vec!["api.sh", "status.sh", "public.sh", "both.sh", "admin.sh"]
+        ]);
+    }
+
+    #[test]
+    fn test_untagged_listener() {
+        let cli = load(r#"
+            port = 8080
+            routes = ["GET:/ a.sh", { method = "GET", path = "/b", handler = "b.sh", tags = "public" }]
+        "#);
+
+        assert_eq!(served(&cli), vec![vec!["a.sh", "b.sh"]]);
+    }
+}
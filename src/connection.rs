@@ -0,0 +1,81 @@
+use crate::tls::TlsInfo;
+use std::{fmt, net::{IpAddr, SocketAddr}};
+
+
+/// Details of the connection a request came in on, added to the request
+/// extensions by the server and passed on to handlers as env vars
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ConnectionInfo {
+    pub tls: Option<TlsInfo>,
+    /// Process on the other end of a Unix socket
+    pub peer: Option<PeerCred>,
+    /// Address of the client, the one a trusted proxy forwarded for when
+    /// behind one
+    pub remote: Option<RemoteAddr>
+}
+
+impl ConnectionInfo {
+    pub fn is_tls(&self) -> bool {
+        self.tls.is_some()
+    }
+
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = self.tls.as_ref()
+            .map(TlsInfo::env)
+            .unwrap_or_default();
+
+        if let Some(remote) = &self.remote {
+            env.push(("REMOTE_ADDR", remote.ip.to_string()));
+            if let Some(port) = remote.port {
+                env.push(("REMOTE_PORT", port.to_string()));
+            }
+        }
+
+        if let Some(peer) = &self.peer {
+            env.push(("SHELL_SERVE_PEER_UID", peer.uid.to_string()));
+            env.push(("SHELL_SERVE_PEER_GID", peer.gid.to_string()));
+            if let Some(pid) = peer.pid {
+                env.push(("SHELL_SERVE_PEER_PID", pid.to_string()));
+            }
+        }
+
+        env
+    }
+}
+
+/// Credentials of a Unix socket peer, from `SO_PEERCRED`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct PeerCred {
+    pub uid: u32,
+    /// Primary group of the peer
+    pub gid: u32,
+    pub pid: Option<i32>
+}
+
+impl From<tokio::net::unix::UCred> for PeerCred {
+    fn from(cred: tokio::net::unix::UCred) -> Self {
+        PeerCred { uid: cred.uid(), gid: cred.gid(), pid: cred.pid() }
+    }
+}
+
+/// Client IP address, and port unless a proxy forwarded the address without it
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RemoteAddr {
+    pub ip: IpAddr,
+    pub port: Option<u16>
+}
+
+impl From<SocketAddr> for RemoteAddr {
+    fn from(addr: SocketAddr) -> Self {
+        RemoteAddr { ip: addr.ip().to_canonical(), port: Some(addr.port()) }
+    }
+}
+
+impl fmt::Display for RemoteAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.port {
+            Some(port) => write!(f, "{}", SocketAddr::new(self.ip, port)),
+            None => write!(f, "{}", self.ip)
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{route::handler_command, Error};
+use hyper::StatusCode;
+use std::{path::{Path, PathBuf}, str::FromStr};
+use tokio::process::Command;
+
+
+/// Which response statuses an error page applies to
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StatusMatch {
+    /// Exact status, e.g. `404`
+    Exact(StatusCode),
+    /// Class of statuses by first digit, e.g. `5xx`
+    Class(u16),
+    /// `408 Request Timeout` and `504 Gateway Timeout`
+    Timeout
+}
+
+impl StatusMatch {
+    pub fn matches(&self, status: StatusCode) -> bool {
+        match self {
+            StatusMatch::Exact(s) => *s == status,
+            StatusMatch::Class(c) => status.as_u16() / 100 == *c,
+            StatusMatch::Timeout => status == StatusCode::REQUEST_TIMEOUT
+                || status == StatusCode::GATEWAY_TIMEOUT
+        }
+    }
+}
+
+impl FromStr for StatusMatch {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidStatus(s.to_string());
+
+        if s == "timeout" {
+            Ok(StatusMatch::Timeout)
+        } else if let Some(class) = s.strip_suffix("xx") {
+            match class.parse() {
+                Ok(c) if (4..=5).contains(&c) => Ok(StatusMatch::Class(c)),
+                _ => Err(invalid())
+            }
+        } else {
+            let status = s.parse::<u16>()
+                .map_err(|_| invalid())?;
+            StatusCode::from_u16(status)
+                .map(StatusMatch::Exact)
+                .map_err(|_| invalid())
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ErrorPage {
+    /// Command that writes the error body to stdout
+    Handler(String),
+    /// Static file sent as the error body
+    File { path: PathBuf, content_type: Option<String> }
+}
+
+/// Error pages, exact status matches take precedence over `timeout`, which
+/// takes precedence over status classes
+#[derive(Debug, Clone, Default)]
+pub struct ErrorPages(Vec<(StatusMatch, ErrorPage)>);
+
+impl ErrorPages {
+    pub fn new(pages: Vec<(StatusMatch, ErrorPage)>) -> Self {
+        ErrorPages(pages)
+    }
+
+    pub fn find(&self, status: StatusCode) -> Option<&ErrorPage> {
+        self.0.iter()
+            .filter(|(m, _)| m.matches(status))
+            .min_by_key(|(m, _)| match m {
+                StatusMatch::Exact(_) => 0,
+                StatusMatch::Timeout => 1,
+                StatusMatch::Class(_) => 2
+            })
+            .map(|(_, page)| page)
+    }
+}
+
+/// Original request and failure, passed to error page handlers
+pub struct ErrorContext<'a> {
+    pub status: StatusCode,
+    pub reason: String,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub request_id: &'a str
+}
+
+impl ErrorContext<'_> {
+    /// Build error page handler command, the request and failure are passed
+    /// as `${name}` args and in the environment
+    pub fn command(&self, handler: &str) -> Result<Command, Error> {
+        let names = ["status", "reason", "method", "path", "request_id"]
+            .map(String::from);
+        let values = [
+            self.status.as_u16().to_string(),
+            self.reason.clone(),
+            self.method.to_string(),
+            self.path.to_string(),
+            self.request_id.to_string()
+        ];
+
+        let mut cmd = handler_command(handler, names.iter().zip(values).collect())?;
+
+        cmd.env("SHELL_SERVE_ERROR_STATUS", self.status.as_u16().to_string())
+            .env("SHELL_SERVE_ERROR_REASON", &self.reason)
+            .env("SHELL_SERVE_REQUEST_METHOD", self.method)
+            .env("SHELL_SERVE_REQUEST_PATH", self.path)
+            .env("SHELL_SERVE_REQUEST_ID", self.request_id);
+
+        Ok(cmd)
+    }
+}
+
+/// Guess content type of error page file from it's extension
+pub fn file_content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        _ => "text/plain; charset=utf-8"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_match_parse() {
+        assert_eq!("404".parse::<StatusMatch>().unwrap(), StatusMatch::Exact(StatusCode::NOT_FOUND));
+        assert_eq!("5xx".parse::<StatusMatch>().unwrap(), StatusMatch::Class(5));
+        assert_eq!("timeout".parse::<StatusMatch>().unwrap(), StatusMatch::Timeout);
+        assert!("2xx".parse::<StatusMatch>().is_err());
+        assert!("abc".parse::<StatusMatch>().is_err());
+    }
+
+    #[test]
+    fn test_error_pages_find() {
+        let pages = ErrorPages::new(vec![
+            ("5xx".parse().unwrap(), ErrorPage::Handler("server_error.sh".to_string())),
+            ("502".parse().unwrap(), ErrorPage::Handler("bad_gateway.sh".to_string())),
+            ("timeout".parse().unwrap(), ErrorPage::Handler("timeout.sh".to_string()))
+        ]);
+
+        assert_eq!(
+            pages.find(StatusCode::BAD_GATEWAY),
+            Some(&ErrorPage::Handler("bad_gateway.sh".to_string()))
+        );
+        assert_eq!(
+            pages.find(StatusCode::INTERNAL_SERVER_ERROR),
+            Some(&ErrorPage::Handler("server_error.sh".to_string()))
+        );
+        assert_eq!(
+            pages.find(StatusCode::GATEWAY_TIMEOUT),
+            Some(&ErrorPage::Handler("timeout.sh".to_string()))
+        );
+        assert_eq!(
+            pages.find(StatusCode::REQUEST_TIMEOUT),
+            Some(&ErrorPage::Handler("timeout.sh".to_string()))
+        );
+        assert_eq!(pages.find(StatusCode::NOT_FOUND), None);
+    }
+}
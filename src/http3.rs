@@ -0,0 +1,193 @@
+use crate::{
+    connection::ConnectionInfo,
+    router::ShellRouter,
+    tls::{ClientCert, TlsInfo}
+};
+use bytes::{Buf, Bytes};
+use futures_util::stream;
+use h3::{
+    error::{Code, StreamError},
+    server::{RequestResolver, RequestStream}
+};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::{body::Frame, Request, Response};
+use quinn::{crypto::rustls::{HandshakeData, QuicServerConfig}, Endpoint};
+use rustls::{pki_types::CertificateDer, ServerConfig};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::{sync::watch, task::JoinSet};
+
+
+/// ALPN protocol of HTTP/3
+pub const ALPN: &[u8] = b"h3";
+
+/// QUIC endpoint on UDP `addr`, `tls` should offer the `h3` ALPN protocol
+pub fn endpoint(addr: SocketAddr, tls: ServerConfig) -> Result<Endpoint, Http3Error> {
+    let crypto = QuicServerConfig::try_from(tls)
+        .map_err(|e| Http3Error::Config(e.to_string()))?;
+
+    let config = quinn::ServerConfig::with_crypto(Arc::new(crypto));
+    Ok(Endpoint::server(config, addr)?)
+}
+
+/// Serve HTTP/3 connections of `endpoint` with the routes of `router`. Each
+/// connection holds the guard returned by `open` until it's closed, and is
+/// closed once its requests are done after `shutdown` turns true.
+pub async fn serve<G: Send + 'static>(
+    endpoint: Endpoint,
+    router: ShellRouter,
+    open: impl Fn() -> G,
+    shutdown: watch::Receiver<bool>
+) {
+    while let Some(incoming) = endpoint.accept().await {
+        let router = router.clone();
+        let shutdown = shutdown.clone();
+        let guard = open();
+
+        tokio::spawn(async move {
+            let _guard = guard;
+            if let Err(err) = serve_connection(incoming, router, shutdown).await {
+                println!("Failed to serve HTTP/3 connection: {err}");
+            }
+        });
+    }
+}
+
+async fn serve_connection(
+    incoming: quinn::Incoming,
+    router: ShellRouter,
+    mut shutdown: watch::Receiver<bool>
+) -> Result<(), Http3Error> {
+    let conn = incoming.await?;
+    let info = ConnectionInfo {
+        tls: Some(tls_info(&conn)),
+        remote: Some(conn.remote_address().into()),
+        ..Default::default()
+    };
+
+    let mut h3_conn = h3::server::Connection::new(h3_quinn::Connection::new(conn.clone())).await?;
+    let mut requests = JoinSet::new();
+    let mut closing = false;
+
+    loop {
+        tokio::select! {
+            accepted = h3_conn.accept() => match accepted {
+                Ok(Some(resolver)) => {
+                    let router = router.clone();
+                    let info = info.clone();
+
+                    requests.spawn(async move {
+                        if let Err(err) = serve_request(resolver, router, info).await {
+                            if !err.is_h3_no_error() {
+                                println!("Failed to serve HTTP/3 request: {err}");
+                            }
+                        }
+                    });
+                },
+                Ok(None) => break,
+                Err(err) if err.is_h3_no_error() => break,
+                Err(err) => return Err(err.into())
+            },
+            // GOAWAY, requests already accepted are still answered
+            _ = async { shutdown.wait_for(|shutdown| *shutdown).await.is_ok() }, if !closing => {
+                closing = true;
+                h3_conn.shutdown(0).await?;
+            },
+            _ = requests.join_next(), if !requests.is_empty() => {}
+        }
+
+        // clients may keep the connection open after GOAWAY
+        if closing && requests.is_empty() {
+            conn.close(Code::H3_NO_ERROR.value().try_into().unwrap_or_default(), b"");
+            return Ok(());
+        }
+    }
+
+    while requests.join_next().await.is_some() {}
+    Ok(())
+}
+
+async fn serve_request(
+    resolver: RequestResolver<h3_quinn::Connection, Bytes>,
+    router: ShellRouter,
+    info: ConnectionInfo
+) -> Result<(), StreamError> {
+    let (req, stream) = resolver.resolve_request().await?;
+    let (mut send, recv) = stream.split();
+
+    let (parts, ()) = req.into_parts();
+    let mut req = Request::from_parts(parts, request_body(recv));
+    req.extensions_mut().insert(info);
+
+    let response = match router.call(req).await {
+        Ok(response) => response,
+        Err(never) => match never {}
+    };
+
+    let (parts, mut body) = response.into_parts();
+    send.send_response(Response::from_parts(parts, ())).await?;
+
+    while let Some(frame) = body.frame().await {
+        match frame {
+            Ok(frame) => if let Ok(data) = frame.into_data() {
+                send.send_data(data).await?;
+            },
+            Err(err) => {
+                println!("Failed to read HTTP/3 response body: {err}");
+                send.stop_stream(Code::H3_INTERNAL_ERROR);
+                return Ok(());
+            }
+        }
+    }
+
+    send.finish().await
+}
+
+/// Request body read from the receiving side of the request stream
+fn request_body(
+    recv: RequestStream<h3_quinn::RecvStream, Bytes>
+) -> StreamBody<impl stream::Stream<Item = Result<Frame<Bytes>, StreamError>>> {
+    let frames = stream::unfold(Some(recv), |recv| async move {
+        let mut recv = recv?;
+
+        match recv.recv_data().await {
+            Ok(Some(mut data)) => Some((Ok(Frame::data(data.copy_to_bytes(data.remaining()))), Some(recv))),
+            Ok(None) => None,
+            Err(err) => Some((Err(err), None))
+        }
+    });
+
+    StreamBody::new(frames)
+}
+
+/// QUIC always uses TLS 1.3, its cipher suite isn't exposed
+fn tls_info(conn: &quinn::Connection) -> TlsInfo {
+    let handshake = conn.handshake_data()
+        .and_then(|data| data.downcast::<HandshakeData>().ok());
+
+    let client_cert = conn.peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<CertificateDer<'static>>>().ok())
+        .and_then(|certs| certs.first().and_then(|cert| ClientCert::parse(cert)));
+
+    TlsInfo {
+        protocol: "TLSv1.3".to_string(),
+        cipher: None,
+        server_name: handshake.as_ref().and_then(|h| h.server_name.clone()),
+        alpn: handshake.and_then(|h| h.protocol).map(|p| String::from_utf8_lossy(&p).to_string()),
+        client_cert
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Http3Error {
+    #[error("Invalid QUIC TLS config: {0}")]
+    Config(String),
+
+    #[error("Failed to bind QUIC endpoint")]
+    Bind(#[from] std::io::Error),
+
+    #[error("QUIC connection failed")]
+    Connection(#[from] quinn::ConnectionError),
+
+    #[error("HTTP/3 connection failed")]
+    Http3(#[from] h3::error::ConnectionError)
+}
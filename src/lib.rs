@@ -1,6 +1,21 @@
+pub mod builtin;
+pub mod connection;
+pub mod error_page;
+pub mod http3;
+mod multipart;
+mod negotiate;
+mod problem;
+pub mod proxy;
+mod request_body;
+pub mod rewrite;
 pub mod route;
 pub mod router;
 mod router_service;
+pub mod static_files;
+pub mod tls;
+
+use hyper::StatusCode;
+use std::time::Duration;
 
 
 #[derive(thiserror::Error, Debug)]
@@ -23,9 +38,38 @@ pub enum Error {
     #[error("Failed to wait on route cmd")]
     RouteWait(#[source] std::io::Error),
 
+    #[error("Route cmd didn't respond within {0:?}")]
+    RouteTimeout(Duration),
+
     #[error("Route stream io error")]
     RouteIoError(#[from] std::io::Error),
 
     #[error("Failed to open route io stream")]
-    RouteIoOpen
+    RouteIoOpen,
+
+    #[error("Handler passed the request: {0}")]
+    InvalidPass(String),
+
+    #[error("Invalid IP network '{0}'")]
+    InvalidNetwork(String)
+}
+
+impl Error {
+    /// HTTP status to respond with when a route fails with this error
+    pub fn status(&self) -> StatusCode {
+        match self {
+            // handler wrote something we can't turn into a response
+            Error::InvalidHeader(_)
+                | Error::InvalidStatus(_)
+                | Error::InvalidPass(_) => StatusCode::BAD_GATEWAY,
+            Error::RouteTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Error::InvalidMethod(_)
+                | Error::InvalidRoute(_)
+                | Error::RouteSpawn(_)
+                | Error::RouteWait(_)
+                | Error::RouteIoError(_)
+                | Error::RouteIoOpen
+                | Error::InvalidNetwork(_) => StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
 }
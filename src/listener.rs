@@ -0,0 +1,371 @@
+use nix::{
+    sys::socket::{getsockname, getsockopt, sockopt, AddressFamily, SockType, SockaddrLike, SockaddrStorage},
+    unistd::{Group, Uid, User}
+};
+use shell_serve::{
+    connection::{ConnectionInfo, PeerCred},
+    route::Route,
+    tls::{CertPair, ClientCa}
+};
+use std::{
+    fmt, fs, io, net::{AddrParseError, IpAddr, SocketAddr},
+    os::{fd::{AsRawFd, OwnedFd}, unix::fs::{FileTypeExt, PermissionsExt}},
+    path::{Path, PathBuf}, pin::Pin, str::FromStr, task::{Context, Poll}
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream}
+};
+
+
+/// Address to listen on, an IP address or a `unix:` socket path
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ListenAddr {
+    Ip(IpAddr),
+    Unix(PathBuf)
+}
+
+impl FromStr for ListenAddr {
+    type Err = AddrParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => Ok(ListenAddr::Ip(s.parse()?))
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Ip(ip) => write!(f, "{ip}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display())
+        }
+    }
+}
+
+/// Address, protocols and TLS settings of a listener, and the routes it serves
+#[derive(Debug, Clone)]
+pub struct ListenerConfig {
+    pub listen: ListenAddr,
+    pub port: u16,
+    pub socket_file: SocketFile,
+    /// Certificate and key pairs, TLS is off when empty
+    pub tls_certs: Vec<CertPair>,
+    pub tls_client_ca: Option<ClientCa>,
+    pub h2c: bool,
+    pub http3: bool,
+    /// Connections start with a PROXY protocol header, from a proxy in front
+    pub proxy_protocol: bool,
+    /// Only serve routes with one of these tags, all routes when unset
+    pub tags: Option<Vec<String>>,
+    /// Name of the socket activation socket to serve on, instead of binding
+    pub fd_name: Option<String>
+}
+
+impl ListenerConfig {
+    pub fn serves(&self, route: &Route) -> bool {
+        match &self.tags {
+            Some(tags) => route.options().tags.iter().any(|tag| tags.contains(tag)),
+            None => true
+        }
+    }
+}
+
+impl fmt::Display for ListenerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.listen {
+            ListenAddr::Ip(ip) => write!(f, "{}", SocketAddr::new(*ip, self.port)),
+            addr => write!(f, "{addr}")
+        }
+    }
+}
+
+/// Permissions and owner of a Unix socket file
+#[derive(Debug, Clone, Default)]
+pub struct SocketFile {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>
+}
+
+impl SocketFile {
+    /// Parse `user[:group]` owner, by name or id
+    pub fn set_owner(&mut self, owner: &str) -> Result<(), String> {
+        let (user, group) = match owner.split_once(':') {
+            Some((user, group)) => (Some(user).filter(|u| !u.is_empty()), Some(group)),
+            None => (Some(owner), None)
+        };
+
+        self.uid = user.map(user_id).transpose()?;
+        self.gid = match group {
+            // `user:` is the user's login group, like with chown
+            Some("") => self.uid.map(login_group).transpose()?,
+            group => group.map(group_id).transpose()?
+        };
+        Ok(())
+    }
+
+    fn apply(&self, path: &Path) -> io::Result<()> {
+        if let Some(mode) = self.mode {
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+        }
+
+        if self.uid.is_some() || self.gid.is_some() {
+            std::os::unix::fs::chown(path, self.uid, self.gid)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// User id of user name or numeric id
+pub fn user_id(user: &str) -> Result<u32, String> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+
+    match User::from_name(user) {
+        Ok(Some(user)) => Ok(user.uid.as_raw()),
+        _ => Err(format!("unknown user '{user}'"))
+    }
+}
+
+/// Primary group id of user `uid`
+fn login_group(uid: u32) -> Result<u32, String> {
+    match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => Ok(user.gid.as_raw()),
+        _ => Err(format!("unknown user id {uid}"))
+    }
+}
+
+/// Group id of group name or numeric id
+pub fn group_id(group: &str) -> Result<u32, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    match Group::from_name(group) {
+        Ok(Some(group)) => Ok(group.gid.as_raw()),
+        _ => Err(format!("unknown group '{group}'"))
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf)
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr, port: u16, socket_file: &SocketFile) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Ip(ip) => Ok(Listener::Tcp(TcpListener::bind(SocketAddr::new(*ip, port)).await?)),
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+
+                let listener = UnixListener::bind(path)?;
+                socket_file.apply(path)?;
+                Ok(Listener::Unix(listener, path.clone()))
+            }
+        }
+    }
+
+    /// Listen on socket passed by the service manager, e.g. systemd
+    pub fn from_fd(fd: OwnedFd) -> io::Result<Self> {
+        if getsockopt(&fd, sockopt::SockType)? != SockType::Stream {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "passed socket isn't a stream socket"));
+        }
+
+        let addr: SockaddrStorage = getsockname(fd.as_raw_fd())?;
+        match addr.family() {
+            Some(AddressFamily::Inet | AddressFamily::Inet6) => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                Ok(Listener::Tcp(TcpListener::from_std(listener)?))
+            },
+            Some(AddressFamily::Unix) => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                let path = listener.local_addr()?
+                    .as_pathname()
+                    .map(PathBuf::from)
+                    .unwrap_or_default();
+                Ok(Listener::Unix(UnixListener::from_std(listener)?, path))
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "passed socket isn't a TCP or Unix socket"))
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<(Connection, ConnectionInfo)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (tcp, addr) = listener.accept().await?;
+                let conn = ConnectionInfo { remote: Some(addr.into()), ..Default::default() };
+                Ok((Connection::Tcp(tcp), conn))
+            },
+            Listener::Unix(listener, _) => {
+                let (unix, _) = listener.accept().await?;
+                let conn = ConnectionInfo {
+                    peer: unix.peer_cred().ok().map(PeerCred::from),
+                    ..Default::default()
+                };
+                Ok((Connection::Unix(unix), conn))
+            }
+        }
+    }
+
+    /// URL of the listener, or socket path
+    pub fn url(&self, tls: bool) -> String {
+        let scheme = if tls { "https" } else { "http" };
+
+        match self {
+            Listener::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => format!("{scheme}://{addr}"),
+                Err(_) => format!("{scheme}://")
+            },
+            Listener::Unix(_, path) => format!("unix:{}", path.display())
+        }
+    }
+}
+
+/// Remove socket file left behind by a server that's gone, refusing to touch
+/// other files or the socket of a server that's still running
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
+
+    if !meta.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("'{}' exists and isn't a socket", path.display())
+        ));
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("'{}' is in use by another server", path.display())
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            println!("Removing stale socket '{}'", path.display());
+            fs::remove_file(path)
+        },
+        Err(e) => Err(e)
+    }
+}
+
+/// Accepted TCP or Unix socket connection
+pub enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream)
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_read(cx, buf)
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Unix(stream) => Pin::new(stream).poll_write(cx, buf)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Unix(stream) => Pin::new(stream).poll_shutdown(cx)
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>]
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Connection::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs)
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Connection::Tcp(stream) => stream.is_write_vectored(),
+            Connection::Unix(stream) => stream.is_write_vectored()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listen_addr_from_str() {
+        assert_eq!("127.0.0.1".parse(), Ok(ListenAddr::Ip("127.0.0.1".parse().unwrap())));
+        assert_eq!("::".parse(), Ok(ListenAddr::Ip("::".parse().unwrap())));
+        assert_eq!("unix:/run/a.sock".parse(), Ok(ListenAddr::Unix(PathBuf::from("/run/a.sock"))));
+        assert!("localhost".parse::<ListenAddr>().is_err());
+        assert_eq!(ListenAddr::Unix(PathBuf::from("/run/a.sock")).to_string(), "unix:/run/a.sock");
+    }
+
+    #[test]
+    fn test_socket_file_set_owner() {
+        let owner = |owner: &str| {
+            let mut socket_file = SocketFile::default();
+            socket_file.set_owner(owner).map(|_| (socket_file.uid, socket_file.gid))
+        };
+
+        assert_eq!(owner("root"), Ok((Some(0), None)));
+        assert_eq!(owner("0:0"), Ok((Some(0), Some(0))));
+        assert_eq!(owner(":root"), Ok((None, Some(0))));
+        assert_eq!(owner("1234:5678"), Ok((Some(1234), Some(5678))));
+
+        // login group of the user
+        assert_eq!(owner("root:"), Ok((Some(0), Some(0))));
+        assert_eq!(owner(":"), Ok((None, None)));
+
+        assert!(owner("no-such-user").is_err());
+        assert!(owner("root:no-such-group").is_err());
+    }
+
+    #[test]
+    fn test_remove_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.sock");
+        assert!(remove_stale_socket(&path).is_ok());
+
+        // socket of a server that's gone
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+        remove_stale_socket(&path).unwrap();
+        assert!(!path.exists());
+
+        let _listener = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert_eq!(remove_stale_socket(&path).unwrap_err().kind(), io::ErrorKind::AddrInUse);
+        assert!(path.exists());
+
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert_eq!(remove_stale_socket(&file).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert!(file.exists());
+    }
+}
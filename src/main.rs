@@ -1,43 +1,268 @@
 mod cli;
+mod listener;
+mod server;
+mod systemd;
 
 use cli::{Cli, Parser};
-use hyper::{body, server::conn::http1, service::service_fn, Request};
-use hyper_util::rt::TokioIo;
-use shell_serve::router::ShellRouter;
-use std::net::SocketAddr;
-use tokio::net::TcpListener;
+use futures_util::future::try_join_all;
+use listener::ListenerConfig;
+use nix::sys::signal::Signal;
+use server::{Connections, Server};
+use shell_serve::{
+    proxy::TrustedProxies,
+    route::{Route, RouteProcess},
+    router::{RouterConfig, ShellRouter}
+};
+use std::{fs, path::Path, process::ExitCode, time::{Duration, SystemTime}};
+use systemd::Systemd;
+use tokio::signal::unix::{signal, SignalKind};
 
 
-#[tokio::main]
-pub async fn main() -> anyhow::Result<()> {
+/// Time handlers get to exit after SIGTERM, before they're killed
+const KILL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the config file is checked for changes, with `watch_config`
+const CONFIG_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Exits with failure when shutdown had to cut connections or handlers short
+pub fn main() -> anyhow::Result<ExitCode> {
+    // before the runtime starts its threads, as it takes systemd's vars out
+    // of the env
+    let systemd = Systemd::from_env()?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    let result = runtime.block_on(run(systemd));
+    runtime.shutdown_background();
+    result
+}
+
+async fn run(mut systemd: Systemd) -> anyhow::Result<ExitCode> {
     let mut cli = Cli::parse();
 
     if let Some(file_path) = cli.file.clone() {
         cli = cli.load_config(file_path)?;
     }
 
-    let addr = SocketAddr::new(cli.listen, cli.port);
+    let listeners = cli.listeners()?;
+    let file = cli.file.clone();
+    let watch_config = cli.watch_config && file.is_some();
+    let (idle_timeout, drain_timeout) = (cli.idle_timeout, cli.drain_timeout);
+    let limits = cli.connection_limits();
+    let (route_sets, config) = route_sets(cli, &listeners)?;
 
-    let listener = TcpListener::bind(addr).await?;
-    println!("Listening on http://{}", addr);
+    // listeners naming a passed socket take theirs before the others take
+    // what's left in order
+    let mut sockets: Vec<_> = listeners.iter()
+        .map(|listener| listener.fd_name.as_deref().and_then(|name| systemd.take_socket(Some(name))))
+        .collect();
+    for (listener, socket) in listeners.iter().zip(&mut sockets) {
+        if listener.fd_name.is_none() {
+            *socket = systemd.take_socket(None);
+        }
+    }
+
+    for name in systemd.unused_sockets() {
+        println!("Ignoring passed socket '{name}', no listener takes it");
+    }
+
+    // bind all listeners before serving on any, so a bad one stops the server
+    let mut servers = vec![];
+    let mut routers = vec![];
+    for ((listener, socket), routes) in listeners.iter().zip(sockets).zip(route_sets) {
+        let router = ShellRouter::new(routes, config.clone());
+        routers.push(router.clone());
+        servers.push(Server::bind(listener.clone(), router, socket).await?);
+    }
 
-    let router = ShellRouter::new(cli.routes);
+    let connections = Connections::new(limits);
+    let serving = servers.into_iter().map(|server| server.serve(connections.clone()));
+    let serving = tokio::spawn(try_join_all(serving));
+    let idle = async {
+        match idle_timeout {
+            Some(timeout) => connections.idle(Duration::from_secs(timeout)).await,
+            None => std::future::pending().await
+        }
+    };
+    let watchdog = systemd.watchdog();
+    tokio::pin!(serving, idle, watchdog);
 
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+
+    let mut config_poll = tokio::time::interval(CONFIG_WATCH_INTERVAL);
+    let mut config_modified = file.as_deref().and_then(modified);
+
+    systemd.notify("READY=1");
+
+    let mut served = false;
     loop {
-        let (tcp, _) = listener.accept().await?;
-        let io = TokioIo::new(tcp);
+        tokio::select! {
+            // servers only stop on their own when accepting fails
+            result = &mut serving => {
+                result??;
+                served = true;
+                break;
+            },
+            // TLS certificates reload on their own on SIGHUP
+            _ = hangup.recv() => {
+                systemd.reloading();
+                if let Some(file) = &file {
+                    config_modified = modified(file);
+                    reload(Cli::parse(), file, &listeners, &routers);
+                }
+                systemd.notify("READY=1");
+            },
+            _ = config_poll.tick(), if watch_config => {
+                let Some(file) = &file else {
+                    continue;
+                };
+
+                let modified = modified(file);
+                if modified != config_modified {
+                    config_modified = modified;
+                    systemd.reloading();
+                    reload(Cli::parse(), file, &listeners, &routers);
+                    systemd.notify("READY=1");
+                }
+            },
+            _ = terminate.recv() => break,
+            _ = interrupt.recv() => break,
+            _ = &mut idle => {
+                println!("Exiting, no connections for {}s", idle_timeout.unwrap_or_default());
+                break;
+            },
+            _ = &mut watchdog => {}
+        }
+    }
+
+    println!("Shutting down, waiting for open connections and running handlers");
+    systemd.notify("STOPPING=1");
+    connections.shut_down();
 
-        let router = router.clone();
+    // servers finish once their connections are closed, HTTP/3 ones included
+    let drain = async {
+        connections.closed().await;
+        if !served {
+            let _ = (&mut serving).await;
+        }
+        RouteProcess::all_exited().await;
+    };
+    tokio::select! {
+        _ = drain => return Ok(ExitCode::SUCCESS),
+        _ = tokio::time::sleep(Duration::from_secs(drain_timeout)) => {},
+        _ = &mut watchdog => {}
+    }
 
-        let service = service_fn(move |req: Request<body::Incoming>| {
-            let router = router.clone();
-            async move { router.call(req).await }
-        });
+    println!(
+        "Shutdown didn't finish in {}s, terminating {} handlers",
+        drain_timeout,
+        RouteProcess::running()
+    );
+    RouteProcess::signal_all(Signal::SIGTERM);
 
-        tokio::task::spawn(async move {
-            if let Err(err) = http1::Builder::new().serve_connection(io, service).await {
-                println!("Failed to serve connection: {:?}", err);
+    if tokio::time::timeout(KILL_TIMEOUT, RouteProcess::all_exited()).await.is_err() {
+        println!("Killing {} handlers", RouteProcess::running());
+        RouteProcess::signal_all(Signal::SIGKILL);
+    }
+
+    Ok(ExitCode::FAILURE)
+}
+
+/// Routes each listener serves, and the router config, from the options
+fn route_sets(cli: Cli, listeners: &[ListenerConfig]) -> anyhow::Result<(Vec<Vec<Route>>, RouterConfig)> {
+    let route_sets = listeners.iter()
+        .map(|listener| {
+            let routes: Vec<_> = cli.routes.iter()
+                .filter(|route| listener.serves(route))
+                .cloned()
+                .collect();
+
+            if routes.is_empty() {
+                anyhow::bail!("Listener on {} has no routes to serve", listener);
+            }
+            Ok(routes)
+        })
+        .collect::<anyhow::Result<_>>()?;
+
+    let config = RouterConfig {
+        debug: cli.debug,
+        error_pages: cli.error_pages,
+        max_body_size: cli.max_body_size,
+        handler_timeout: cli.handler_timeout.map(Duration::from_secs),
+        temp_dir: cli.temp_dir,
+        default_host: cli.default_host,
+        rewrites: cli.rewrites,
+        trailing_slash: cli.trailing_slash,
+        trusted_proxies: TrustedProxies::new(cli.trusted_proxies, cli.forwarded_header)
+    };
+
+    Ok((route_sets, config))
+}
+
+/// Re-read config `file` over the command line options `cli`, and swap the
+/// new routes into the routers of the running `listeners`. Keeps the current
+/// routes when the config is invalid.
+fn reload(cli: Cli, file: &Path, listeners: &[ListenerConfig], routers: &[ShellRouter]) {
+    let result = cli.load_config(file)
+        .map_err(anyhow::Error::from)
+        .and_then(|cli| route_sets(cli, listeners));
+
+    match result {
+        Ok((route_sets, config)) => {
+            let count: usize = route_sets.iter().map(Vec::len).sum();
+            for (router, routes) in routers.iter().zip(route_sets) {
+                router.replace(routes, config.clone());
             }
-        });
+            println!("Reloaded config from '{}', {count} routes", file.display());
+        },
+        Err(e) => println!("Failed to reload config, keeping current routes: {e:#}")
+    }
+}
+
+fn modified(file: &Path) -> Option<SystemTime> {
+    fs::metadata(file).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shell_serve::{route::RouteAction, router::RouteTable};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("shell-serve.toml");
+        let cli = || Cli::try_parse_from(["shell-serve".as_ref(), "--file".as_ref(), file.as_os_str()]).unwrap();
+        let handlers = |table: Arc<RouteTable>| table.routes().iter()
+            .filter_map(|route| match route.action() {
+                RouteAction::Handler(handler) => Some(handler.clone()),
+                _ => None
+            })
+            .collect::<Vec<_>>();
+
+        fs::write(&file, "routes = [\"GET:/ old.sh\"]").unwrap();
+        let loaded = cli().load_config(&file).unwrap();
+        let listeners = loaded.listeners().unwrap();
+        let (route_sets, config) = route_sets(loaded, &listeners).unwrap();
+        let router = ShellRouter::new(route_sets.into_iter().next().unwrap(), config);
+        let routers = [router.clone()];
+        let old = router.table();
+
+        // a config that fails to parse keeps the current table
+        fs::write(&file, "routes = [\"GET:/ new.sh\"").unwrap();
+        reload(cli(), &file, &listeners, &routers);
+        assert!(Arc::ptr_eq(&old, &router.table()));
+
+        // so does one leaving a listener without routes
+        fs::write(&file, "routes = []").unwrap();
+        reload(cli(), &file, &listeners, &routers);
+        assert!(Arc::ptr_eq(&old, &router.table()));
+
+        fs::write(&file, "routes = [\"GET:/ new.sh\"]").unwrap();
+        reload(cli(), &file, &listeners, &routers);
+        assert_eq!(handlers(router.table()), ["new.sh"]);
+        assert_eq!(handlers(old), ["old.sh"]);
     }
-}
\ No newline at end of file
+}
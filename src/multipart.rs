@@ -0,0 +1,234 @@
+use crate::{
+    request_body::{body_too_large, private_temp_dir},
+    route::RouteOptions,
+    router::RouterError,
+    Error
+};
+use futures_util::Stream;
+use hyper::body::Bytes;
+use serde::Serialize;
+use std::{io::Error as IoError, path::{Path, PathBuf}};
+use tempfile::TempDir;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+
+/// Maximum number of parts when route doesn't set `max_parts`
+pub const DEFAULT_MAX_PARTS: usize = 100;
+
+/// Maximum size of text fields, which are kept in memory, when route doesn't
+/// set `max_part_size`
+pub const DEFAULT_MAX_FIELD_SIZE: u64 = 64 * 1024;
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum ManifestPart {
+    Field { name: String, value: String },
+    File {
+        name: String,
+        filename: String,
+        content_type: Option<String>,
+        path: PathBuf,
+        size: u64
+    }
+}
+
+/// Multipart body parts written to private temp dir, deleted when dropped
+pub struct MultipartBody {
+    _dir: TempDir,
+    pub manifest_path: PathBuf,
+    /// `field.<name>`, `file.<name>.<attr>` and `multipart_manifest` params
+    pub captures: Vec<(String, String)>
+}
+
+/// Write file parts of multipart body to temp files, and collect text fields.
+/// `max_body_size` is the limit the body stream is held to.
+pub async fn spool_multipart<S>(
+    stream: S,
+    boundary: String,
+    options: &RouteOptions,
+    max_body_size: Option<u64>,
+    temp_dir: Option<&Path>
+) -> Result<MultipartBody, RouterError>
+    where S: Stream<Item = Result<Bytes, IoError>> + Send + 'static
+{
+    let dir = private_temp_dir(temp_dir)
+        .map_err(Error::from)?;
+
+    let max_parts = options.max_parts.unwrap_or(DEFAULT_MAX_PARTS);
+    let max_field_size = options.max_part_size
+        .unwrap_or(max_body_size.map_or(DEFAULT_MAX_FIELD_SIZE, |l| l.min(DEFAULT_MAX_FIELD_SIZE)));
+
+    let mut size_limit = multer::SizeLimit::new();
+    if let Some(limit) = options.max_part_size {
+        size_limit = size_limit.per_field(limit);
+    }
+
+    let constraints = multer::Constraints::new()
+        .size_limit(size_limit);
+    let mut multipart = multer::Multipart::with_constraints(stream, boundary, constraints);
+
+    let mut parts = vec![];
+
+    while let Some(mut field) = multipart.next_field().await.map_err(multipart_error)? {
+        if parts.len() >= max_parts {
+            return Err(RouterError::TooManyParts(max_parts));
+        }
+
+        let name = field.name()
+            .unwrap_or_default()
+            .to_string();
+
+        let part = if let Some(filename) = field.file_name() {
+            let filename = filename.to_string();
+            let content_type = field.content_type()
+                .map(|m| m.to_string());
+
+            let path = dir.path().join(format!("part-{}", parts.len()));
+            let mut file = File::create(&path).await
+                .map_err(Error::from)?;
+
+            let mut size = 0;
+            while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+                size += chunk.len() as u64;
+                file.write_all(&chunk).await
+                    .map_err(Error::from)?;
+            }
+            file.flush().await
+                .map_err(Error::from)?;
+
+            ManifestPart::File { name, filename, content_type, path, size }
+        } else {
+            let mut value = vec![];
+            while let Some(chunk) = field.chunk().await.map_err(multipart_error)? {
+                if (value.len() + chunk.len()) as u64 > max_field_size {
+                    return Err(RouterError::PayloadTooLarge(max_field_size));
+                }
+                value.extend_from_slice(&chunk);
+            }
+
+            let value = String::from_utf8_lossy(&value).to_string();
+            ManifestPart::Field { name, value }
+        };
+
+        parts.push(part);
+    }
+
+    let manifest_path = dir.path().join("manifest.json");
+    let manifest = serde_json::to_vec(&parts)
+        .expect("manifest should serialize");
+    tokio::fs::write(&manifest_path, manifest).await
+        .map_err(Error::from)?;
+
+    let mut captures = vec![
+        ("multipart_manifest".to_string(), manifest_path.to_string_lossy().to_string())
+    ];
+
+    // later parts with the same name take precedence
+    for part in parts {
+        match part {
+            ManifestPart::Field { name, value } => {
+                captures.push((format!("field.{name}"), value));
+            },
+            ManifestPart::File { name, filename, content_type, path, size } => {
+                captures.extend([
+                    (format!("file.{name}.path"), path.to_string_lossy().to_string()),
+                    (format!("file.{name}.filename"), filename),
+                    (format!("file.{name}.content_type"), content_type.unwrap_or_default()),
+                    (format!("file.{name}.size"), size.to_string())
+                ]);
+            }
+        }
+    }
+
+    Ok(MultipartBody { _dir: dir, manifest_path, captures })
+}
+
+fn multipart_error(err: multer::Error) -> RouterError {
+    match err {
+        multer::Error::FieldSizeExceeded { limit, .. }
+            | multer::Error::StreamSizeExceeded { limit } => RouterError::PayloadTooLarge(limit),
+        multer::Error::StreamReadFailed(e) => {
+            let e = match e.downcast::<IoError>() {
+                Ok(e) => *e,
+                Err(e) => IoError::other(e)
+            };
+
+            match body_too_large(&e) {
+                Some(limit) => RouterError::PayloadTooLarge(limit),
+                None => Error::from(e).into()
+            }
+        },
+        e => RouterError::InvalidRequest(format!("malformed multipart body: {e}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Multipart body with text fields and one file part per `parts` entry
+    fn body(parts: &[(&str, Option<&str>, &str)]) -> impl Stream<Item = Result<Bytes, IoError>> + Send + 'static {
+        let mut body = String::new();
+        for (name, filename, value) in parts {
+            body.push_str("--X\r\n");
+            match filename {
+                Some(filename) => body.push_str(&format!(
+                    "Content-Disposition: form-data; name=\"{name}\"; filename=\"{filename}\"\r\n\r\n"
+                )),
+                None => body.push_str(&format!("Content-Disposition: form-data; name=\"{name}\"\r\n\r\n"))
+            }
+            body.push_str(value);
+            body.push_str("\r\n");
+        }
+        body.push_str("--X--\r\n");
+
+        futures_util::stream::iter([Ok(Bytes::from(body))])
+    }
+
+    async fn spool(
+        parts: &[(&str, Option<&str>, &str)],
+        options: RouteOptions,
+        max_body_size: Option<u64>
+    ) -> Result<MultipartBody, RouterError> {
+        spool_multipart(body(parts), "X".to_string(), &options, max_body_size, None).await
+    }
+
+    #[tokio::test]
+    async fn test_spool_multipart() {
+        let parts = spool(&[("user", None, "ann"), ("image", Some("a.png"), "png data")], RouteOptions::default(), None)
+            .await
+            .unwrap();
+
+        let capture = |name: &str| parts.captures.iter().find(|(k, _)| k == name).map(|(_, v)| v.clone());
+        assert_eq!(capture("field.user").as_deref(), Some("ann"));
+        assert_eq!(capture("file.image.filename").as_deref(), Some("a.png"));
+        assert_eq!(capture("file.image.size").as_deref(), Some("8"));
+        assert_eq!(std::fs::read_to_string(capture("file.image.path").unwrap()).unwrap(), "png data");
+    }
+
+    #[tokio::test]
+    async fn test_spool_multipart_limits() {
+        let options = RouteOptions { max_parts: Some(1), ..Default::default() };
+        let result = spool(&[("a", None, "1"), ("b", None, "2")], options, None).await;
+        assert!(matches!(result, Err(RouterError::TooManyParts(1))));
+
+        let options = RouteOptions { max_part_size: Some(4), ..Default::default() };
+        let result = spool(&[("a", Some("a.txt"), "too large")], options, None).await;
+        assert!(matches!(result, Err(RouterError::PayloadTooLarge(4))));
+
+        let options = RouteOptions { max_part_size: Some(4), ..Default::default() };
+        let result = spool(&[("a", None, "too large")], options, None).await;
+        assert!(matches!(result, Err(RouterError::PayloadTooLarge(4))));
+
+        // text fields are capped without a part size limit, by default or the body limit
+        let large = "x".repeat(DEFAULT_MAX_FIELD_SIZE as usize + 1);
+        let result = spool(&[("a", None, &large)], RouteOptions::default(), None).await;
+        assert!(matches!(result, Err(RouterError::PayloadTooLarge(DEFAULT_MAX_FIELD_SIZE))));
+
+        let result = spool(&[("a", None, "too large")], RouteOptions::default(), Some(4)).await;
+        assert!(matches!(result, Err(RouterError::PayloadTooLarge(4))));
+
+        // files aren't held to the text field limit
+        assert!(spool(&[("a", Some("a.txt"), &large)], RouteOptions::default(), None).await.is_ok());
+    }
+}
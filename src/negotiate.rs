@@ -0,0 +1,134 @@
+/// Media range from `Accept` header, e.g. `text/*;q=0.5`
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaRange {
+    kind: String,
+    subtype: String,
+    quality: f32
+}
+
+impl MediaRange {
+    fn parse(s: &str) -> Option<Self> {
+        let mut params = s.split(';');
+
+        let (kind, subtype) = params.next()?
+            .trim()
+            .split_once('/')?;
+
+        let mut quality = 1.0;
+        for param in params {
+            if let Some((name, value)) = param.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("q") {
+                    quality = value.trim().parse().ok()
+                        .filter(|q| (0.0..=1.0).contains(q))?;
+                }
+            }
+        }
+
+        Some(MediaRange {
+            kind: kind.trim().to_ascii_lowercase(),
+            subtype: subtype.trim().to_ascii_lowercase(),
+            quality
+        })
+    }
+
+    /// Specificity of range when it matches `kind/subtype`, higher is more specific
+    fn specificity(&self, kind: &str, subtype: &str) -> Option<u8> {
+        match (self.kind.as_str(), self.subtype.as_str()) {
+            ("*", "*") => Some(0),
+            (k, "*") if k == kind => Some(1),
+            (k, s) if k == kind && s == subtype => Some(2),
+            _ => None
+        }
+    }
+}
+
+/// Parse `Accept` header value, invalid ranges are ignored
+pub fn parse_accept(accept: &str) -> Vec<MediaRange> {
+    accept.split(',')
+        .filter_map(MediaRange::parse)
+        .collect()
+}
+
+/// Media type without parameters, in lower case
+fn essence(media_type: &str) -> String {
+    media_type.split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Quality of `media_type` given by most specific matching range in `accept`
+pub fn quality(accept: &[MediaRange], media_type: &str) -> f32 {
+    let media_type = essence(media_type);
+    let (kind, subtype) = media_type.split_once('/')
+        .unwrap_or((&media_type, ""));
+
+    accept.iter()
+        .filter_map(|r| r.specificity(kind, subtype).map(|s| (s, r.quality)))
+        .max_by_key(|(specificity, _)| *specificity)
+        .map_or(0.0, |(_, quality)| quality)
+}
+
+/// Choose media type with highest quality from types a route produces,
+/// without an `Accept` header all types are acceptable
+pub fn best_media_type<'a>(produces: &'a [String], accept: Option<&[MediaRange]>) -> (f32, Option<&'a String>) {
+    let Some(accept) = accept else {
+        return (1.0, produces.first());
+    };
+
+    produces.iter()
+        .map(|t| (quality(accept, t), t))
+        .fold((0.0, None), |best, (q, t)| if q > best.0 { (q, Some(t)) } else { best })
+}
+
+/// Request content type matches any of the patterns a route consumes, patterns
+/// may use wildcards, e.g. `text/*`
+pub fn content_type_matches(consumes: &[String], content_type: &str) -> bool {
+    let content_type = essence(content_type);
+    let (kind, subtype) = content_type.split_once('/')
+        .unwrap_or((&content_type, ""));
+
+    consumes.iter()
+        .filter_map(|p| MediaRange::parse(p))
+        .any(|p| p.specificity(kind, subtype).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quality() {
+        let accept = parse_accept("text/html, application/*;q=0.5, */*;q=0.1, text/plain;q=0");
+
+        assert_eq!(quality(&accept, "text/html"), 1.0);
+        assert_eq!(quality(&accept, "text/html; charset=utf-8"), 1.0);
+        assert_eq!(quality(&accept, "application/json"), 0.5);
+        assert_eq!(quality(&accept, "image/png"), 0.1);
+        assert_eq!(quality(&accept, "text/plain"), 0.0);
+        assert_eq!(quality(&parse_accept("text/html"), "application/json"), 0.0);
+    }
+
+    #[test]
+    fn test_best_media_type() {
+        let produces = vec!["application/json".to_string(), "text/csv".to_string()];
+
+        let accept = parse_accept("text/csv;q=0.9, application/json;q=0.8");
+        assert_eq!(best_media_type(&produces, Some(&accept)), (0.9, Some(&produces[1])));
+
+        let accept = parse_accept("text/html");
+        assert_eq!(best_media_type(&produces, Some(&accept)), (0.0, None));
+
+        assert_eq!(best_media_type(&produces, None), (1.0, Some(&produces[0])));
+    }
+
+    #[test]
+    fn test_content_type_matches() {
+        let consumes = vec!["application/json".to_string(), "text/*".to_string()];
+
+        assert!(content_type_matches(&consumes, "application/json; charset=utf-8"));
+        assert!(content_type_matches(&consumes, "text/csv"));
+        assert!(!content_type_matches(&consumes, "application/xml"));
+    }
+}
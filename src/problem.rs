@@ -0,0 +1,92 @@
+use hyper::StatusCode;
+use serde::Serialize;
+use std::error::Error as StdError;
+
+pub const CONTENT_TYPE: &str = "application/problem+json";
+
+/// RFC 7807 "problem details" response body
+#[derive(Serialize, Debug)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    pub request_id: String
+}
+
+impl Problem {
+    /// Build problem details for `err`, the error message is only included
+    /// for client errors unless `debug` is set, to avoid leaking internals
+    pub fn new(status: StatusCode, err: &dyn StdError, request_id: &str, debug: bool) -> Self {
+        let detail = if debug {
+            Some(error_chain(err))
+        } else if status.is_client_error() {
+            Some(err.to_string())
+        } else {
+            None
+        };
+
+        Problem {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Unknown").to_string(),
+            status: status.as_u16(),
+            detail,
+            request_id: request_id.to_string()
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self)
+            .expect("problem should serialize")
+    }
+}
+
+/// Format error and all of it's sources, e.g. "outer: inner: root cause"
+pub fn error_chain(err: &dyn StdError) -> String {
+    let mut msg = err.to_string();
+    let mut source = err.source();
+
+    while let Some(e) = source {
+        msg.push_str(": ");
+        msg.push_str(&e.to_string());
+        source = e.source();
+    }
+
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{router::RouterError, Error};
+
+    #[test]
+    fn test_problem_hides_internal_detail() {
+        let err = RouterError::from(Error::RouteSpawn(std::io::Error::other("no such file")));
+
+        let problem = Problem::new(err.status(), &err, "abc", false);
+        assert_eq!(problem.status, 500);
+        assert_eq!(problem.detail, None);
+        assert_eq!(
+            problem.to_json(),
+            r#"{"type":"about:blank","title":"Internal Server Error","status":500,"request_id":"abc"}"#
+        );
+
+        let problem = Problem::new(err.status(), &err, "abc", true);
+        assert_eq!(
+            problem.detail.as_deref(),
+            Some("Route handler failed: Failed to spawn route cmd: no such file")
+        );
+    }
+
+    #[test]
+    fn test_problem_client_error_detail() {
+        let err = RouterError::UnsupportedMethod("PATCH".to_string());
+
+        let problem = Problem::new(err.status(), &err, "abc", false);
+        assert_eq!(problem.status, 405);
+        assert_eq!(problem.detail.as_deref(), Some("Unsupported method 'PATCH'"));
+    }
+}
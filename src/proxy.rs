@@ -0,0 +1,347 @@
+use crate::{connection::RemoteAddr, Error};
+use hyper::{header, HeaderMap};
+use std::{
+    io, net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+
+/// Signature starting a PROXY protocol v2 header
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest PROXY protocol v1 header, including the CRLF
+const V1_MAX_LEN: usize = 107;
+
+/// IP network in CIDR notation, e.g. `10.0.0.0/8`, or a single address
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients of dual stack sockets show up as mapped IPv6 addresses
+        let (net, ip, bits) = match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net).into(), u32::from(ip).into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false
+        };
+
+        (net ^ ip).checked_shr(bits - u32::from(self.prefix)).unwrap_or(0) == 0
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidNetwork(s.to_string());
+
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None)
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|p| *p <= bits).ok_or_else(invalid)?,
+            None => bits
+        };
+
+        Ok(IpNet { addr: addr.to_canonical(), prefix })
+    }
+}
+
+/// Header trusted proxies pass the client address on in. Only the one the
+/// proxies set can be trusted, clients can send the other themselves.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded`
+    Forwarded
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(ForwardedHeader::XForwardedFor),
+            "forwarded" => Ok(ForwardedHeader::Forwarded),
+            _ => Err(format!("invalid forwarded header '{s}', expected x-forwarded-for or forwarded"))
+        }
+    }
+}
+
+/// Proxies trusted to pass on the address of the client they forward for
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+    header: ForwardedHeader
+}
+
+impl TrustedProxies {
+    pub fn new(nets: Vec<IpNet>, header: ForwardedHeader) -> Self {
+        TrustedProxies { nets, header }
+    }
+
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        self.nets.iter().any(|net| net.contains(ip))
+    }
+
+    /// Client a request from `remote` came from. While the address is a
+    /// trusted proxy, the one it forwarded for is taken from the configured
+    /// header, going back from the nearest proxy.
+    pub fn client(&self, remote: RemoteAddr, headers: &HeaderMap) -> RemoteAddr {
+        if !self.trusts(remote.ip) {
+            return remote;
+        }
+
+        let forwarded: Vec<_> = match self.header {
+            ForwardedHeader::Forwarded => header_list(headers, header::FORWARDED.as_str())
+                .map(|element| forwarded_for(element).and_then(parse_node))
+                .collect(),
+            ForwardedHeader::XForwardedFor => header_list(headers, "x-forwarded-for")
+                .map(parse_node)
+                .collect()
+        };
+
+        let mut client = remote;
+        for addr in forwarded.into_iter().rev() {
+            // unknown or obfuscated addresses end the chain at the proxy
+            let Some(addr) = addr else {
+                break;
+            };
+
+            client = addr;
+            if !self.trusts(client.ip) {
+                break;
+            }
+        }
+
+        client
+    }
+}
+
+/// Comma separated values of all `name` headers
+fn header_list<'a>(headers: &'a HeaderMap, name: &str) -> impl Iterator<Item = &'a str> {
+    headers.get_all(name).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+}
+
+/// `for` parameter of a `Forwarded` element, e.g. `for=192.0.2.60;proto=http`
+fn forwarded_for(element: &str) -> Option<&str> {
+    element.split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("for"))
+        .map(|(_, value)| value.trim().trim_matches('"'))
+}
+
+/// Address with an optional port, e.g. `192.0.2.60`, `[2001:db8::1]:4711`
+fn parse_node(node: &str) -> Option<RemoteAddr> {
+    let node = node.trim();
+
+    if let Ok(ip) = node.parse() {
+        return Some(RemoteAddr { ip, port: None });
+    }
+
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.into());
+    }
+
+    node.strip_prefix('[')?
+        .strip_suffix(']')?
+        .parse()
+        .ok()
+        .map(|ip| RemoteAddr { ip, port: None })
+}
+
+/// Read the PROXY protocol v1 or v2 header a proxy sends at the start of a
+/// connection. `None` when the header has no client IP address, e.g. for the
+/// proxy's own health checks.
+pub async fn read_proxy_header<R>(io: &mut R) -> io::Result<Option<SocketAddr>>
+    where R: AsyncRead + Unpin
+{
+    // the shortest v1 header, "PROXY UNKNOWN\r\n", is longer than this
+    let mut start = [0; 12];
+    io.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        io.read_exact(&mut header).await?;
+        let mut addrs = vec![0; usize::from(u16::from_be_bytes([header[2], header[3]]))];
+        io.read_exact(&mut addrs).await?;
+
+        return parse_v2(header[0], header[1], &addrs);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid_header());
+    }
+
+    // read up to the CRLF, and not past it into the request
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid_header());
+        }
+        line.push(io.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid_header())?;
+    parse_v1(line)
+}
+
+/// Parse v1 header line without the CRLF, e.g.
+/// `PROXY TCP4 192.0.2.60 192.0.2.1 56324 443`
+fn parse_v1(line: &str) -> io::Result<Option<SocketAddr>> {
+    let fields: Vec<_> = line.split(' ').collect();
+
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _, src_port, _] => {
+            let ip = src.parse().map_err(|_| invalid_header())?;
+            let port = src_port.parse().map_err(|_| invalid_header())?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        _ => Err(invalid_header())
+    }
+}
+
+/// Parse v2 header after the signature, from its version and command,
+/// address family and protocol, and address block
+fn parse_v2(version_command: u8, family: u8, addrs: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid_header());
+    }
+
+    // LOCAL command, sent by the proxy on its own behalf
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    match family >> 4 {
+        // AF_INET
+        1 if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addrs[..4]).unwrap());
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        },
+        // AF_INET6
+        2 if addrs.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[..16]).unwrap());
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        },
+        // AF_UNSPEC and AF_UNIX
+        0 | 3 => Ok(None),
+        _ => Err(invalid_header())
+    }
+}
+
+fn invalid_header() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid PROXY protocol header")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_net_contains() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.200.3".parse().unwrap()));
+        assert!(net.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8:1::7".parse().unwrap()));
+        assert!(!net.contains("2001:db9::7".parse().unwrap()));
+
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains("192.0.2.1".parse().unwrap()));
+        assert!("127.0.0.1".parse::<IpNet>().unwrap().contains("127.0.0.1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("localhost".parse::<IpNet>().is_err());
+    }
+
+    fn remote(addr: &str) -> RemoteAddr {
+        RemoteAddr::from(addr.parse::<SocketAddr>().unwrap())
+    }
+
+    /// Client of a request from `remote` with `headers`, behind proxies in
+    /// `10.0.0.0/8` setting `header`
+    fn client(header: ForwardedHeader, remote: RemoteAddr, headers: &[(&str, &str)]) -> String {
+        let proxies = TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()], header);
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(header::HeaderName::from_bytes(name.as_bytes()).unwrap(), value.parse().unwrap());
+        }
+        proxies.client(remote, &map).to_string()
+    }
+
+    #[test]
+    fn test_trusted_proxies_client() {
+        let client = |remote, headers: &[_]| client(ForwardedHeader::XForwardedFor, remote, headers);
+        let proxy = remote("10.0.0.1:5000");
+        assert_eq!(client(proxy, &[("x-forwarded-for", "192.0.2.1, 198.51.100.7, 10.0.0.2")]), "198.51.100.7");
+        assert_eq!(client(proxy, &[("x-forwarded-for", "192.0.2.1"), ("x-forwarded-for", "10.0.0.9")]), "192.0.2.1");
+        assert_eq!(client(proxy, &[]), "10.0.0.1:5000");
+
+        // headers from clients that aren't proxies are ignored
+        assert_eq!(client(remote("192.0.2.9:80"), &[("x-forwarded-for", "127.0.0.1")]), "192.0.2.9:80");
+    }
+
+    #[test]
+    fn test_trusted_proxies_forwarded() {
+        let client = |remote, headers: &[_]| client(ForwardedHeader::Forwarded, remote, headers);
+        let proxy = remote("10.0.0.1:5000");
+        assert_eq!(client(proxy, &[("forwarded", "for=192.0.2.1, for=\"[2001:db8::7]:4711\";proto=https")]), "[2001:db8::7]:4711");
+        assert_eq!(client(proxy, &[("forwarded", "for=unknown")]), "10.0.0.1:5000");
+        assert_eq!(client(proxy, &[("x-forwarded-for", "192.0.2.1")]), "10.0.0.1:5000");
+    }
+
+    #[test]
+    fn test_forwarded_spoofed() {
+        // a proxy only appending X-Forwarded-For passes on the client's own
+        // Forwarded header untouched
+        let headers = [("forwarded", "for=10.0.0.5"), ("x-forwarded-for", "192.0.2.1")];
+        assert_eq!(client(ForwardedHeader::XForwardedFor, remote("10.0.0.1:5000"), &headers), "192.0.2.1");
+
+        // and the other way around
+        let headers = [("x-forwarded-for", "10.0.0.5"), ("forwarded", "for=192.0.2.1")];
+        assert_eq!(client(ForwardedHeader::Forwarded, remote("10.0.0.1:5000"), &headers), "192.0.2.1");
+    }
+
+    #[test]
+    fn test_forwarded_header_from_str() {
+        assert_eq!("x-forwarded-for".parse(), Ok(ForwardedHeader::XForwardedFor));
+        assert_eq!("Forwarded".parse(), Ok(ForwardedHeader::Forwarded));
+        assert!("x-real-ip".parse::<ForwardedHeader>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_proxy_header() {
+        let mut io: &[u8] = b"PROXY TCP4 192.0.2.1 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(read_proxy_header(&mut io).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(io, b"GET / HTTP/1.1\r\n");
+
+        let mut io: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_proxy_header(&mut io).await.unwrap(), None);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x11, 0, 12, 192, 0, 2, 1, 10, 0, 0, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        v2.extend(b"GET");
+        let mut io = &v2[..];
+        assert_eq!(read_proxy_header(&mut io).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(io, b"GET");
+
+        let mut io: &[u8] = b"GET / HTTP/1.1\r\nHost: x\r\n";
+        assert!(read_proxy_header(&mut io).await.is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use futures_util::{future, stream, Stream, StreamExt, TryStreamExt};
+use http_body_util::{combinators::UnsyncBoxBody, BodyExt, BodyStream};
+use hyper::body::{self, Body};
+use std::{error::Error as StdError, io::Error as IoError, path::Path};
+use tempfile::{NamedTempFile, TempDir};
+use tokio::io::{self, AsyncRead, AsyncWriteExt};
+use tokio_util::io::StreamReader;
+
+
+#[derive(thiserror::Error, Debug)]
+#[error("Request body exceeds limit of {0} bytes")]
+pub struct BodyTooLarge(u64);
+
+/// Limit that was exceeded, if `err` was caused by `BodyTooLarge`
+pub fn body_too_large(err: &IoError) -> Option<u64> {
+    err.get_ref()
+        .and_then(|e| e.downcast_ref::<BodyTooLarge>())
+        .map(|e| e.0)
+}
+
+/// Body of a request from any of the HTTP versions
+type IncomingBody = UnsyncBoxBody<body::Bytes, IoError>;
+
+/// Request body, with any data read ahead for matching routes kept in memory
+pub struct RequestBody {
+    buffered: Vec<u8>,
+    /// Rest of the body, `None` for copies of a fully buffered body
+    incoming: Option<IncomingBody>
+}
+
+impl RequestBody {
+    pub fn new<B>(incoming: B) -> Self
+        where B: Body<Data = body::Bytes> + Send + 'static,
+              B::Error: Into<Box<dyn StdError + Send + Sync>>
+    {
+        let incoming = incoming.map_err(IoError::other).boxed_unsync();
+        RequestBody { buffered: vec![], incoming: Some(incoming) }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.incoming.as_ref().is_none_or(|i| i.is_end_stream())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffered.is_empty() && self.is_end_stream()
+    }
+
+    /// Lower bound of body size, i.e. `Content-Length` when it's known
+    pub fn size_hint(&self) -> u64 {
+        let incoming = self.incoming.as_ref()
+            .map_or(0, |i| i.size_hint().lower());
+        self.buffered.len() as u64 + incoming
+    }
+
+    /// Copy of body, if it has been read ahead completely
+    pub fn try_clone(&self) -> Option<Self> {
+        self.is_end_stream()
+            .then(|| RequestBody { buffered: self.buffered.clone(), incoming: None })
+    }
+
+    /// Read whole body into memory, or `None` if it's larger than `limit`,
+    /// in which case the data read so far is still kept for streaming
+    pub async fn read_ahead(&mut self, limit: usize) -> Result<Option<&[u8]>, IoError> {
+        if self.size_hint() > limit as u64 {
+            return Ok(None);
+        }
+
+        while let Some(incoming) = self.incoming.as_mut().filter(|i| !i.is_end_stream()) {
+            let frame = match incoming.frame().await {
+                Some(frame) => frame?,
+                None => break
+            };
+
+            if let Ok(data) = frame.into_data() {
+                self.buffered.extend_from_slice(&data);
+            }
+
+            if self.buffered.len() > limit {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(&self.buffered))
+    }
+
+    /// Body as stream of bytes, that fails with `BodyTooLarge` once more
+    /// than `limit` bytes have been read
+    pub fn into_stream(self, limit: Option<u64>) -> impl Stream<Item = Result<body::Bytes, IoError>> {
+        let buffered = Some(body::Bytes::from(self.buffered))
+            .filter(|b| !b.is_empty())
+            .map(Ok);
+
+        let incoming = stream::iter(self.incoming)
+            .flat_map(BodyStream::new)
+            .try_filter_map(|frame| async move { Ok(frame.into_data().ok()) });
+
+        let mut total = 0;
+        stream::iter(buffered)
+            .chain(incoming)
+            .and_then(move |data| {
+                total += data.len() as u64;
+                let result = match limit {
+                    Some(limit) if total > limit => Err(IoError::other(BodyTooLarge(limit))),
+                    _ => Ok(data)
+                };
+                future::ready(result)
+            })
+    }
+
+    pub fn into_reader(self, limit: Option<u64>) -> impl AsyncRead {
+        StreamReader::new(self.into_stream(limit))
+    }
+}
+
+/// Create private temp file, in `temp_dir` or the system temp dir
+pub fn temp_file(temp_dir: Option<&Path>) -> Result<NamedTempFile, IoError> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("shell-serve-");
+
+    match temp_dir {
+        Some(dir) => builder.tempfile_in(dir),
+        None => builder.tempfile()
+    }
+}
+
+/// Create private temp directory, in `temp_dir` or the system temp dir
+pub fn private_temp_dir(temp_dir: Option<&Path>) -> Result<TempDir, IoError> {
+    let mut builder = tempfile::Builder::new();
+    builder.prefix("shell-serve-");
+
+    match temp_dir {
+        Some(dir) => builder.tempdir_in(dir),
+        None => builder.tempdir()
+    }
+}
+
+/// Write request body to temp file, the file is deleted when dropped
+pub async fn spool_to_file<R>(reader: &mut R, temp_dir: Option<&Path>) -> Result<NamedTempFile, IoError>
+    where R: AsyncRead + Unpin
+{
+    let temp = temp_file(temp_dir)?;
+
+    let mut file = tokio::fs::File::from_std(temp.as_file().try_clone()?);
+    io::copy(reader, &mut file).await?;
+    file.flush().await?;
+
+    Ok(temp)
+}
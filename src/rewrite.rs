@@ -0,0 +1,309 @@
+use crate::{
+    route::{Method, RouteRequest},
+    Error
+};
+use hyper::StatusCode;
+use regex::Regex;
+use std::path::{Component, PathBuf};
+
+
+/// Passes over the rewrite rules before the path is considered looping
+const MAX_REWRITE_PASSES: usize = 10;
+
+/// Regex condition, negated with a `!` prefix
+#[derive(Debug, Clone)]
+struct Condition {
+    regex: Regex,
+    negate: bool
+}
+
+impl Condition {
+    fn new(pattern: &str) -> Result<Self, Error> {
+        let (pattern, negate) = match pattern.strip_prefix('!') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, false)
+        };
+
+        Ok(Condition { regex: rewrite_regex(pattern)?, negate })
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        self.regex.is_match(value) != self.negate
+    }
+}
+
+/// Condition on a request header, the regex is negated with a `!` prefix
+#[derive(Debug, Clone)]
+pub struct HeaderCondition {
+    name: String,
+    condition: Condition
+}
+
+impl HeaderCondition {
+    pub fn new(name: &str, pattern: &str) -> Result<Self, Error> {
+        Ok(HeaderCondition {
+            name: name.to_ascii_lowercase(),
+            condition: Condition::new(pattern)?
+        })
+    }
+
+    fn matches(&self, req: &RouteRequest) -> bool {
+        let value = req.headers.get(&self.name)
+            .map(String::as_str)
+            .unwrap_or_default();
+
+        self.condition.matches(value)
+    }
+}
+
+/// Regex rewrite of the request path, applied before routes are matched
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pattern: Regex,
+    /// Replacement path, with `$1` or `${name}` capture groups, and optional query
+    target: String,
+    method: Option<Method>,
+    /// Condition on the raw query string, empty without a query
+    query: Option<Condition>,
+    conditions: Vec<HeaderCondition>,
+    /// Stop rewriting after this rule
+    pub last: bool,
+    /// Apply the rules again after this pass, when the rule applied
+    pub next: bool,
+    /// Respond with redirect to the rewritten path, instead of routing it
+    pub redirect: Option<StatusCode>
+}
+
+impl RewriteRule {
+    pub fn new(pattern: &str, target: String) -> Result<Self, Error> {
+        Ok(RewriteRule {
+            pattern: rewrite_regex(pattern)?,
+            target,
+            method: None,
+            query: None,
+            conditions: vec![],
+            last: false,
+            next: false,
+            redirect: None
+        })
+    }
+
+    pub fn with_method(mut self, method: Method) -> Self {
+        self.method = Some(method);
+        self
+    }
+
+    pub fn with_condition(mut self, condition: HeaderCondition) -> Self {
+        self.conditions.push(condition);
+        self
+    }
+
+    /// Only apply when the raw query string matches `pattern`, negated with
+    /// a `!` prefix
+    pub fn with_query(mut self, pattern: &str) -> Result<Self, Error> {
+        self.query = Some(Condition::new(pattern)?);
+        Ok(self)
+    }
+
+    /// Rewritten path and query, if the rule applies to `req` with the raw
+    /// `query` string
+    fn apply(&self, req: &RouteRequest, query: Option<&str>) -> Option<String> {
+        if self.method.as_ref().is_some_and(|m| *m != req.method) {
+            return None;
+        }
+
+        if self.query.as_ref().is_some_and(|c| !c.matches(query.unwrap_or_default())) {
+            return None;
+        }
+
+        let path = req.path.to_string_lossy();
+        let captures = self.pattern.captures(&path)?;
+
+        if !self.conditions.iter().all(|c| c.matches(req)) {
+            return None;
+        }
+
+        let mut target = String::new();
+        captures.expand(&self.target, &mut target);
+        Some(target)
+    }
+}
+
+fn rewrite_regex(pattern: &str) -> Result<Regex, Error> {
+    Regex::new(pattern)
+        .map_err(|e| Error::InvalidRoute(format!("invalid rewrite pattern '{pattern}': {e}")))
+}
+
+/// Apply rewrite rules in order, starting over when a `next` rule applied and
+/// no `last` rule matched. `query` is the raw query string, replaced when a
+/// target has a query. Returns the status for a `redirect` rule.
+pub fn apply_rewrites(
+    rules: &[RewriteRule],
+    req: &mut RouteRequest,
+    query: &mut Option<String>
+) -> Result<Option<StatusCode>, Error> {
+    let mut seen = vec![];
+
+    for _ in 0..MAX_REWRITE_PASSES {
+        // compared as strings, paths that only differ in slashes are equal
+        let before = (req.path.as_os_str().to_owned(), query.clone());
+        let mut next = false;
+
+        for rule in rules {
+            let Some(target) = rule.apply(req, query.as_deref()) else {
+                continue;
+            };
+            next |= rule.next;
+
+            if let Some(target_query) = rewrite_request(req, &target)? {
+                *query = Some(target_query);
+            }
+
+            if rule.redirect.is_some() || rule.last {
+                return Ok(rule.redirect);
+            }
+        }
+
+        if !next || (req.path.as_os_str(), &*query) == (&*before.0, &before.1) {
+            return Ok(None);
+        }
+
+        if seen.contains(&before) {
+            break;
+        }
+        seen.push(before);
+    }
+
+    Err(Error::InvalidRoute(format!("rewrite loop for '{}'", req.path.display())))
+}
+
+/// Replace request path with rewrite `target`, and the query when the target
+/// has one, which is returned
+pub(crate) fn rewrite_request(req: &mut RouteRequest, target: &str) -> Result<Option<String>, Error> {
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (target, None)
+    };
+
+    let path = PathBuf::from(path);
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return Err(Error::InvalidRoute(format!("invalid rewrite target '{target}'")));
+    }
+    req.path = path;
+
+    if let Some(query) = query {
+        req.query = urlparse::parse_qs(query).into_iter()
+            .map(|(key, val)| (key, val.join(",")))
+            .collect();
+    }
+
+    Ok(query.map(String::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rewrite(rules: &[RewriteRule], req: &str) -> Result<(String, Option<String>, Option<StatusCode>), Error> {
+        let mut req: RouteRequest = req.parse().unwrap();
+        let mut query = None;
+        let status = apply_rewrites(rules, &mut req, &mut query)?;
+        Ok((req.path.to_string_lossy().to_string(), query, status))
+    }
+
+    #[test]
+    fn test_apply_rewrites() {
+        let rules = vec![
+            RewriteRule::new(r"^(.*)\.php$", "$1".to_string()).unwrap(),
+            RewriteRule::new(r"^/u/(?<id>\d+)$", "/users/${id}?from=u".to_string()).unwrap()
+        ];
+
+        assert_eq!(rewrite(&rules, "GET:/index.php").unwrap(), ("/index".to_string(), None, None));
+        assert_eq!(
+            rewrite(&rules, "GET:/u/123.php").unwrap(),
+            ("/users/123".to_string(), Some("from=u".to_string()), None)
+        );
+        assert_eq!(rewrite(&rules, "GET:/other").unwrap(), ("/other".to_string(), None, None));
+    }
+
+    #[test]
+    fn test_apply_rewrites_flags() {
+        let mut redirect = RewriteRule::new(r"^/old/(.*)$", "/new/$1".to_string()).unwrap();
+        redirect.redirect = Some(StatusCode::MOVED_PERMANENTLY);
+
+        let rules = vec![
+            redirect,
+            RewriteRule::new(r"^/(.*)$", "/app/$1".to_string()).unwrap()
+                .with_method(Method::Post)
+        ];
+
+        assert_eq!(
+            rewrite(&rules, "GET:/old/x").unwrap(),
+            ("/new/x".to_string(), None, Some(StatusCode::MOVED_PERMANENTLY))
+        );
+        assert_eq!(rewrite(&rules, "GET:/x").unwrap(), ("/x".to_string(), None, None));
+
+        // rules apply once, unless a rule asks for the next pass
+        assert_eq!(rewrite(&rules, "POST:/x").unwrap(), ("/app/x".to_string(), None, None));
+
+        let mut next = RewriteRule::new(r"^/(.*)$", "/app/$1".to_string()).unwrap();
+        next.next = true;
+        assert!(rewrite(&[next.clone()], "POST:/x").is_err());
+
+        let mut last = RewriteRule::new(r"^/(.*)$", "/app/$1".to_string()).unwrap();
+        last.last = true;
+        assert_eq!(rewrite(&[last, next], "POST:/x").unwrap(), ("/app/x".to_string(), None, None));
+    }
+
+    #[test]
+    fn test_apply_rewrites_prefix() {
+        // matches its own result again
+        let rules = vec![RewriteRule::new("^/old(.*)$", "/old/new$1".to_string()).unwrap()];
+        assert_eq!(rewrite(&rules, "GET:/old").unwrap(), ("/old/new".to_string(), None, None));
+        assert_eq!(rewrite(&rules, "GET:/old/x").unwrap(), ("/old/new/x".to_string(), None, None));
+
+        let mut rules = rules;
+        rules[0].next = true;
+        assert!(rewrite(&rules, "GET:/old").is_err());
+
+        // `next` passes end when the path stops changing
+        let mut strip = RewriteRule::new(r"^(.*)/$", "$1".to_string()).unwrap();
+        strip.next = true;
+        assert_eq!(rewrite(&[strip], "GET:/a///").unwrap(), ("/a".to_string(), None, None));
+    }
+
+    #[test]
+    fn test_query_condition() {
+        let rule = RewriteRule::new("^/search$", "/find?legacy=1".to_string()).unwrap()
+            .with_query("(^|&)q=").unwrap();
+        let rules = [rule];
+
+        let mut req: RouteRequest = "GET:/search".parse().unwrap();
+        let mut query = Some("page=2&q=x".to_string());
+        apply_rewrites(&rules, &mut req, &mut query).unwrap();
+        assert_eq!((req.path, query), (PathBuf::from("/find"), Some("legacy=1".to_string())));
+
+        assert_eq!(rewrite(&rules, "GET:/search").unwrap().0, "/search");
+
+        let negated = RewriteRule::new("^/search$", "/find".to_string()).unwrap()
+            .with_query("!q=").unwrap();
+        assert_eq!(rewrite(&[negated], "GET:/search").unwrap().0, "/find");
+    }
+
+    #[test]
+    fn test_header_condition() {
+        let rule = RewriteRule::new("^/$", "/mobile".to_string()).unwrap()
+            .with_condition(HeaderCondition::new("User-Agent", "Mobile").unwrap());
+        let rules = [rule];
+
+        let mut req: RouteRequest = "GET:/".parse().unwrap();
+        req.headers.insert("user-agent".to_string(), "Mobile Safari".to_string());
+        apply_rewrites(&rules, &mut req, &mut None).unwrap();
+        assert_eq!(req.path, PathBuf::from("/mobile"));
+
+        assert_eq!(rewrite(&rules, "GET:/").unwrap().0, "/");
+
+        let negated = HeaderCondition::new("user-agent", "!Mobile").unwrap();
+        assert!(negated.matches(&"GET:/".parse().unwrap()));
+    }
+}
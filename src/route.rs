@@ -1,14 +1,20 @@
 mod process;
 mod request;
 mod response;
-pub use process::RouteProcess;
-pub use request::RouteRequest;
+pub use process::{RouteProcess, PASS_EXIT_CODE};
+pub use request::{BodyFields, RouteRequest};
 pub use response::RouteResponse;
 
-use crate::Error;
+use crate::{
+    builtin::{FixedResponse, Redirect},
+    connection::ConnectionInfo,
+    proxy::IpNet,
+    static_files::StaticDir,
+    tls::ClientCertCondition,
+    Error
+};
 use std::{
-    collections::{HashMap, VecDeque}, os::fd::{AsRawFd, OwnedFd},
-    path::{Component, Path}, process::Stdio, str::FromStr
+    collections::{HashMap, VecDeque}, path::{Component, Path, PathBuf}, str::FromStr
 };
 use tokio::process::Command;
 
@@ -97,37 +103,302 @@ impl FromStr for QueryPart {
     }
 }
 
+/// Condition on a form field or JSON value in the request body, the field is
+/// `.name` (dotted path) or `/name` (JSON pointer)
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct BodyPart {
+    field: String,
+    value: RoutePart
+}
+
+impl FromStr for BodyPart {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = s.split_once('=')
+            .ok_or(Error::InvalidRoute("invalid key=value entry in body".to_string()))?;
+
+        let field = name.strip_prefix("body")
+            .filter(|f| f.len() > 1 && (f.starts_with('.') || f.starts_with('/')))
+            .ok_or(Error::InvalidRoute(format!("body entry '{name}' must start with 'body.' or 'body/'")))?;
+
+        Ok(BodyPart { field: field.to_string(), value: value.parse()? })
+    }
+}
+
+/// Named values captured by matching a route
+pub type RouteParams<'a> = Vec<(&'a String, String)>;
+
+/// How the request body is passed to the handler
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub enum BodyMode {
+    /// Streamed to handler stdin
+    #[default]
+    Stdin,
+    /// Written to temp file, with path in `${body_file}`
+    File,
+    /// Parsed as `multipart/form-data`, with fields and files as params
+    Multipart
+}
+
+impl FromStr for BodyMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdin" => Ok(BodyMode::Stdin),
+            "file" => Ok(BodyMode::File),
+            "multipart" => Ok(BodyMode::Multipart),
+            _ => Err(Error::InvalidRoute(format!("invalid body mode '{s}'")))
+        }
+    }
+}
+
+/// How request paths that differ from the route only in trailing or repeated
+/// slashes are handled
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum TrailingSlash {
+    /// Match regardless of trailing and repeated slashes
+    #[default]
+    Ignore,
+    /// Only match the canonical path
+    Strict,
+    /// Redirect to the canonical path with `308 Permanent Redirect`
+    Redirect
+}
+
+impl FromStr for TrailingSlash {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(TrailingSlash::Ignore),
+            "strict" => Ok(TrailingSlash::Strict),
+            "redirect" => Ok(TrailingSlash::Redirect),
+            _ => Err(Error::InvalidRoute(format!("invalid trailing slash policy '{s}'")))
+        }
+    }
+}
+
+/// Per-route settings that aren't part of the route definition
+#[derive(Debug, Clone, Default)]
+pub struct RouteOptions {
+    /// Overrides the server wide request body size limit
+    pub max_body_size: Option<u64>,
+    pub body: BodyMode,
+    /// Maximum number of parts in multipart body
+    pub max_parts: Option<usize>,
+    /// Maximum size of each part in multipart body
+    pub max_part_size: Option<u64>,
+    /// Media types the route responds with, negotiated with `Accept`
+    pub accept: Option<Vec<String>>,
+    /// Media types of request bodies the route handles
+    pub content_type: Option<Vec<String>>,
+    /// Directories handlers may name in `X-Sendfile`
+    pub sendfile: Option<Vec<PathBuf>>,
+    /// Handler may pass the request on to the next matching route
+    pub may_pass: bool,
+    /// Start the response while the handler runs, once its output pauses
+    pub stream: bool,
+    /// Overrides the server wide trailing slash policy
+    pub trailing_slash: Option<TrailingSlash>,
+    /// Client certificate must match one of these conditions
+    pub client_cert: Option<Vec<ClientCertCondition>>,
+    /// Unix socket peer must run as one of these users
+    pub peer_uid: Option<Vec<u32>>,
+    /// Unix socket peer must have one of these primary groups
+    pub peer_gid: Option<Vec<u32>>,
+    /// Client address must be in one of these networks
+    pub client_ip: Option<Vec<IpNet>>,
+    /// Names of route sets the route is in, that listeners pick routes by
+    pub tags: Vec<String>
+}
+
+impl RouteOptions {
+    /// Route has conditions on the client connection
+    pub fn has_client_conditions(&self) -> bool {
+        self.client_cert.is_some()
+            || self.peer_uid.is_some()
+            || self.peer_gid.is_some()
+            || self.client_ip.is_some()
+    }
+
+    /// Client connection meets all of the route's client conditions
+    pub fn allows_client(&self, conn: &ConnectionInfo) -> bool {
+        let client_cert = conn.tls.as_ref()
+            .and_then(|tls| tls.client_cert.as_ref());
+
+        let cert_allowed = match (&self.client_cert, client_cert) {
+            (Some(conditions), Some(cert)) => conditions.iter().any(|c| c.matches(cert)),
+            (Some(_), None) => false,
+            (None, _) => true
+        };
+
+        let id_allowed = |ids: &Option<Vec<u32>>, id: Option<u32>| match (ids, id) {
+            (Some(ids), Some(id)) => ids.contains(&id),
+            (Some(_), None) => false,
+            (None, _) => true
+        };
+
+        let ip_allowed = match (&self.client_ip, conn.remote) {
+            (Some(nets), Some(remote)) => nets.iter().any(|net| net.contains(remote.ip)),
+            (Some(_), None) => false,
+            (None, _) => true
+        };
+
+        cert_allowed
+            && ip_allowed
+            && id_allowed(&self.peer_uid, conn.peer.map(|p| p.uid))
+            && id_allowed(&self.peer_gid, conn.peer.map(|p| p.gid))
+    }
+}
+
+/// What a route does with a matched request
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RouteAction {
+    /// Command run with the request body on stdin
+    Handler(String),
+    /// Files served directly from a directory
+    Static(StaticDir),
+    /// Fixed response from config
+    Respond(FixedResponse),
+    Redirect(Redirect),
+    /// Replace request path, then match routes again
+    Rewrite(String)
+}
+
 #[derive(Debug, Clone)]
 pub struct Route {
     method: Method,
+    host: Option<Vec<PathPart>>,
     path: Vec<PathPart>,
+    /// Route path ends with a slash, e.g. `/docs/`
+    trailing_slash: bool,
     query: Option<Vec<QueryPart>>,
     headers: Option<Vec<QueryPart>>,
-    handler: String
+    body: Option<Vec<BodyPart>>,
+    action: RouteAction,
+    options: RouteOptions
 }
 
 impl FromStr for Route {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (method, path) = s.split_once(':')
+        let (pattern, handler) = s.split_once(' ')
+            .ok_or(Error::InvalidRoute("missing handler separator (space)".to_string()))?;
+
+        Route::new(pattern, RouteAction::Handler(handler.to_string()))
+    }
+}
+
+/// Parse host pattern into labels, only the first label may be a catch-all
+fn parse_host(host: &str) -> Result<Vec<PathPart>, Error> {
+    // split on dots outside of braces, catch-all names contain dots
+    let mut labels = vec![];
+    let mut start = 0;
+    let mut in_braces = false;
+    for (i, c) in host.char_indices() {
+        match c {
+            '{' => in_braces = true,
+            '}' => in_braces = false,
+            '.' if !in_braces => {
+                labels.push(&host[start..i]);
+                start = i + 1;
+            },
+            _ => ()
+        }
+    }
+    labels.push(&host[start..]);
+
+    let labels = labels.into_iter()
+        .map(PathPart::from_str)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let catch_all_pos = labels.iter()
+        .position(|l| matches!(l, PathPart::CatchAll(_)));
+    if catch_all_pos.is_some_and(|pos| pos > 0) {
+        return Err(Error::InvalidRoute("catch-all is only allowed in first host label".to_string()));
+    }
+
+    Ok(labels)
+}
+
+/// Match request host against host pattern labels
+fn match_host<'a>(pattern: &'a [PathPart], host: &str) -> Option<Vec<(&'a String, String)>> {
+    let labels: Vec<_> = host.split('.').collect();
+
+    // so captured labels can't hold `..`
+    if labels.iter().any(|label| label.is_empty()) {
+        return None;
+    }
+
+    let mut params = vec![];
+
+    let (pattern, labels) = match pattern.first() {
+        Some(PathPart::CatchAll(n)) => {
+            // catch-all matches one or more leading labels
+            let count = labels.len()
+                .checked_sub(pattern.len() - 1)
+                .filter(|c| *c > 0)?;
+            params.push((n, labels[..count].join(".")));
+            (&pattern[1..], &labels[count..])
+        },
+        _ => (pattern, &labels[..])
+    };
+
+    if pattern.len() != labels.len() {
+        return None;
+    }
+
+    for (part, label) in pattern.iter().zip(labels) {
+        match part {
+            PathPart::Entry(RoutePart::Literal(v)) if v.eq_ignore_ascii_case(label) => (),
+            PathPart::Entry(RoutePart::Named(n) | RoutePart::NamedOptional(n)) => {
+                params.push((n, label.to_string()));
+            },
+            _ => return None
+        }
+    }
+
+    Some(params)
+}
+
+impl Route {
+    /// Route from `METHOD:PATH` pattern, without the handler
+    pub fn new(pattern: &str, action: RouteAction) -> Result<Self, Error> {
+        let (method, path) = pattern.split_once(':')
             .ok_or(Error::InvalidRoute("missing method separator (:)".to_string()))?;
 
-        let (path, handler) = path.split_once(' ')
-            .ok_or(Error::InvalidRoute("missing handler separator (space)".to_string()))?;
+        let (path, body) = match path.split_once('$') {
+            Some((path, body)) => {
+                let body = body.split('&')
+                    .map(BodyPart::from_str)
+                    .collect::<Result<Vec<_>, _>>();
+
+                (path, Some(body?))
+            },
+            None => (path, None)
+        };
 
         let path_uri = urlparse::urlparse(path);
 
+        let host = if !path_uri.netloc.is_empty() {
+            Some(parse_host(&path_uri.netloc)?)
+        } else {
+            None
+        };
+
+        let trailing_slash = path_uri.path.len() > 1 && path_uri.path.ends_with('/');
+
         let path = path_uri.path.split('/')
-            .into_iter()
             .filter(|s| !s.is_empty())
-            .map(|s| PathPart::from_str(s))
+            .map(PathPart::from_str)
             .collect::<Result<Vec<_>, _>>();
 
         let query = if let Some(query) = path_uri.query {
             let query = query.split('&')
-                .into_iter()
-                .map(|s| QueryPart::from_str(s))
+                .map(QueryPart::from_str)
                 .collect::<Result<Vec<_>, _>>();
 
             Some(query?)
@@ -137,8 +408,7 @@ impl FromStr for Route {
 
         let headers = if let Some(fragment) = path_uri.fragment {
             let headers = fragment.split('&')
-                .into_iter()
-                .map(|s| QueryPart::from_str(s))
+                .map(QueryPart::from_str)
                 .collect::<Result<Vec<_>, _>>();
 
             Some(headers?)
@@ -148,35 +418,89 @@ impl FromStr for Route {
 
         Ok(Route {
             method: Method::from_str(method)?,
+            host,
             path: path?,
+            trailing_slash,
             query,
             headers,
-            handler: handler.to_string()
+            body,
+            action,
+            options: RouteOptions::default()
         })
     }
-}
 
-impl Route {
-    pub fn get_command(&self, params: Vec<(&String, String)>) -> Result<Command, Error> {
-        let mut parts = self.handler.split(' ');
+    /// Set host pattern, unless route definition already has a host
+    pub fn with_host(mut self, host: &str) -> Result<Self, Error> {
+        if self.host.is_none() {
+            self.host = Some(parse_host(host)?);
+        }
+        Ok(self)
+    }
+
+    /// Route has host pattern that matches the request host
+    pub fn matches_host(&self, req: &RouteRequest) -> bool {
+        match (&self.host, &req.host) {
+            (Some(pattern), Some(host)) => match_host(pattern, host).is_some(),
+            _ => false
+        }
+    }
 
-        let cmd = parts.next()
-            .ok_or(Error::InvalidRoute("missing handler cmd".to_string()))?;
+    pub fn with_options(mut self, options: RouteOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Add route to more route sets, e.g. those of its host group
+    pub fn with_tags(mut self, tags: &[String]) -> Self {
+        for tag in tags {
+            if !self.options.tags.contains(tag) {
+                self.options.tags.push(tag.clone());
+            }
+        }
+        self
+    }
 
-        let mut cmd = Command::new(cmd);
+    pub fn options(&self) -> &RouteOptions {
+        &self.options
+    }
 
-        let ctx: HashMap<_, _> = params.into_iter()
+    pub fn action(&self) -> &RouteAction {
+        &self.action
+    }
+
+    /// Request `path` with repeated slashes collapsed, and a trailing slash
+    /// only when the route has one. Routes ending with a catch-all keep the
+    /// trailing slash of the request.
+    pub fn canonical_path(&self, path: &str) -> String {
+        let segments: Vec<_> = path.split('/')
+            .filter(|s| !s.is_empty())
             .collect();
 
-        for arg in parts {
-            cmd.arg(
-                shellexpand::env_with_context_no_errors(arg,
-                    |var| ctx.get(&String::from(var))
-                ).to_string()
-            );
+        let trailing_slash = match self.path.last() {
+            Some(PathPart::CatchAll(_)) => path.ends_with('/'),
+            _ => self.trailing_slash
+        };
+
+        let mut canonical = format!("/{}", segments.join("/"));
+        if trailing_slash && !segments.is_empty() {
+            canonical.push('/');
         }
+        canonical
+    }
 
-        Ok(cmd)
+    /// Name of the catch-all capture at the end of the path, if any
+    pub fn path_catch_all(&self) -> Option<&String> {
+        match self.path.last() {
+            Some(PathPart::CatchAll(name)) => Some(name),
+            _ => None
+        }
+    }
+
+    pub fn get_command(&self, params: Vec<(&String, String)>) -> Result<Command, Error> {
+        match &self.action {
+            RouteAction::Handler(handler) => handler_command(handler, params),
+            _ => Err(Error::InvalidRoute("route has no handler".to_string()))
+        }
     }
 
     pub fn matches(&self, req: &RouteRequest) -> Option<Vec<(&String, String)>> {
@@ -193,6 +517,10 @@ impl Route {
 
         let mut params = vec![];
 
+        if let Some(pattern) = &self.host {
+            params.extend(match_host(pattern, req.host.as_deref()?)?);
+        }
+
         let result = PathMatchIterator::new(self.path.iter(), path)
             .matches();
         if let Some(matches) = result {
@@ -221,32 +549,67 @@ impl Route {
             }
         }
 
+        if let Some(route_body) = &self.body {
+            let fields = req.body.as_ref()?;
+
+            for part in route_body {
+                match (fields.get(&part.field), &part.value) {
+                    (Some(v), RoutePart::Literal(l)) if *l == v => (),
+                    (Some(v), RoutePart::Named(n) | RoutePart::NamedOptional(n)) => {
+                        params.push((n, v));
+                    },
+                    (None, RoutePart::NamedOptional(n)) => {
+                        params.push((n, String::new()));
+                    },
+                    _ => return None
+                }
+            }
+        }
+
         Some(params)
     }
 
+    /// Route matches on request body fields, so the body must be read first
+    pub fn matches_body(&self) -> bool {
+        self.body.is_some()
+    }
+
     pub fn spawn(&self, params: Vec<(&String, String)>) -> Result<RouteProcess, Error> {
-        let mut cmd = self.get_command(params)?;
+        RouteProcess::spawn(self.get_command(params)?)
+    }
+}
 
-        let (read_pipe, write_pipe) = os_pipe::pipe()?;
-        let write_pipe_fd: OwnedFd = write_pipe.into();
+/// Build command from handler definition, expanding `${name}` args with `params`
+pub(crate) fn handler_command(handler: &str, params: Vec<(&String, String)>) -> Result<Command, Error> {
+    let mut parts = handler.split(' ');
 
-        // FIXME could this be made cross platform, or at least work on MacOS?
-        let write_pipe_path = format!("/proc/{}/fd/{}",
-            std::process::id(),
-            write_pipe_fd.as_raw_fd()
-        );
+    let cmd = parts.next()
+        .ok_or(Error::InvalidRoute("missing handler cmd".to_string()))?;
 
-        cmd.env("SHELL_SERVE_PIPE", write_pipe_path);
+    let mut cmd = Command::new(cmd);
 
-        let child = cmd
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .map_err(|e| Error::RouteSpawn(e))?;
+    let ctx: HashMap<_, _> = params.into_iter()
+        .collect();
 
-        Ok(RouteProcess::new(child, read_pipe, write_pipe_fd))
+    for arg in parts {
+        cmd.arg(
+            shellexpand::env_with_context_no_errors(arg,
+                |var| ctx.get(&String::from(var))
+            ).to_string()
+        );
     }
+
+    Ok(cmd)
+}
+
+/// Expand `${name}` in `template` with `params`, unknown names are left as is
+pub(crate) fn expand_params(template: &str, params: &RouteParams<'_>) -> String {
+    let ctx: HashMap<_, _> = params.iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .collect();
+
+    shellexpand::env_with_context_no_errors(template, |var| ctx.get(var))
+        .to_string()
 }
 
 enum MatchResult<'a> {
@@ -334,6 +697,7 @@ where
         let haystack = path.components()
             .map(|c| match c {
                 Component::Normal(v) => v.to_str().unwrap().to_string(),
+                Component::ParentDir => "..".to_string(),
                 _ => panic!("Unexpected path component variant")
             })
             .collect();
@@ -348,11 +712,7 @@ where
     type Item = MatchResult<'a>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let path_part = if let Some(path_part) = self.iter.next() {
-            path_part
-        } else {
-            return None;
-        };
+        let path_part = self.iter.next()?;
 
         match path_part {
             PathPart::Entry(entry) => {
@@ -369,22 +729,27 @@ where
                             Some(MatchResult::NoMatch)
                         }
                     },
+                    // captures never hold `..`, handlers may use them in file paths
                     RoutePart::Named(n) => {
-                        if let Some(val) = path_entry {
-                            Some(MatchResult::Match(n, val))
-                        } else {
-                            Some(MatchResult::NoMatch)
+                        match path_entry {
+                            Some(val) if val != ".." => Some(MatchResult::Match(n, val)),
+                            _ => Some(MatchResult::NoMatch)
                         }
                     },
                     RoutePart::NamedOptional(n) => {
-                        let val = path_entry.unwrap_or(String::new());
-                        Some(MatchResult::Match(n, val))
+                        match path_entry.unwrap_or_default() {
+                            val if val == ".." => Some(MatchResult::NoMatch),
+                            val => Some(MatchResult::Match(n, val))
+                        }
                     }
                 }
             },
             PathPart::CatchAll(n) => {
                 let remaining: Vec<_> = self.haystack.drain(..).collect();
-                return Some(MatchResult::Match(n, remaining.join("/")));
+                if remaining.iter().any(|v| v == "..") {
+                    return Some(MatchResult::NoMatch);
+                }
+                Some(MatchResult::Match(n, remaining.join("/")))
             }
         }
     }
@@ -394,7 +759,7 @@ trait MatchIterator<'a>: Iterator<Item = MatchResult<'a>> {
     fn matches(&mut self) -> Option<Vec<(&'a String, String)>> {
         let mut matches = vec![];
 
-        while let Some(x) = self.next() {
+        for x in &mut *self {
             match x {
                 MatchResult::Match(k, v) => matches.push((k, v)),
                 MatchResult::MatchLiteral => (),
@@ -406,7 +771,7 @@ trait MatchIterator<'a>: Iterator<Item = MatchResult<'a>> {
             return None;
         }
 
-        return Some(matches);
+        Some(matches)
     }
 
     fn haystack_count(&self) -> usize;
@@ -437,6 +802,11 @@ mod tests {
             self.headers = headers.into();
             self
         }
+
+        fn with_body(mut self, content_type: &str, body: &str) -> Self {
+            self.body = BodyFields::parse(content_type, body.as_bytes());
+            self
+        }
     }
 
     #[test]
@@ -450,7 +820,7 @@ mod tests {
             PathPart::Entry(RoutePart::Literal("foo".to_string())),
             PathPart::Entry(RoutePart::Named("file".to_string()))
         ]);
-        assert_eq!(route.handler, "handler_get_foo.sh ${file}");
+        assert_eq!(route.action, RouteAction::Handler("handler_get_foo.sh ${file}".to_string()));
     }
 
     #[test]
@@ -536,6 +906,8 @@ mod tests {
             route.matches(&"GET:/".parse().unwrap()),
             Some(vec![(&String::from("path"), String::from(""))])
         );
+
+        assert_eq!(route.matches(&"GET:/foo/../etc/passwd".parse().unwrap()), None);
     }
 
     #[test]
@@ -606,4 +978,121 @@ mod tests {
             ])
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_route_match_json_body() {
+        let route = Route::from_str("POST:/hook$body.action={action}&body.repository.name={repo}&body/ref={ref*} handler.sh ${action} ${repo}");
+        assert!(route.is_ok());
+        let route = route.unwrap();
+
+        let req = RouteRequest::from_str("POST:/hook").unwrap()
+            .with_body("application/json", r#"{"action":"opened","repository":{"name":"foo","id":1}}"#);
+
+        assert_eq!(
+            route.matches(&req),
+            Some(vec![
+                (&String::from("action"), String::from("opened")),
+                (&String::from("repo"), String::from("foo")),
+                (&String::from("ref"), String::from(""))
+            ])
+        );
+
+        let req = RouteRequest::from_str("POST:/hook").unwrap()
+            .with_body("application/json", r#"{"action":"opened"}"#);
+        assert_eq!(route.matches(&req), None);
+
+        assert_eq!(route.matches(&"POST:/hook".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_route_match_form_body() {
+        let route = Route::from_str("POST:/login$body.user={user}&body.remember=yes handler.sh ${user}");
+        assert!(route.is_ok());
+        let route = route.unwrap();
+
+        let req = RouteRequest::from_str("POST:/login").unwrap()
+            .with_body("application/x-www-form-urlencoded; charset=utf-8", "user=bob&remember=yes&other=1");
+        assert_eq!(
+            route.matches(&req),
+            Some(vec![(&String::from("user"), String::from("bob"))])
+        );
+
+        let req = RouteRequest::from_str("POST:/login").unwrap()
+            .with_body("application/x-www-form-urlencoded", "user=bob&remember=no");
+        assert_eq!(route.matches(&req), None);
+
+        assert!(Route::from_str("POST:/login$user={user} handler.sh").is_err());
+    }
+
+    #[test]
+    fn test_route_match_host() {
+        let route = Route::from_str("GET://{tenant}.example.test/{file} handler.sh ${tenant} ${file}");
+        assert!(route.is_ok());
+        let route = route.unwrap();
+
+        assert_eq!(
+            route.matches(&"GET://acme.example.test/foo.txt".parse().unwrap()),
+            Some(vec![
+                (&String::from("tenant"), String::from("acme")),
+                (&String::from("file"), String::from("foo.txt"))
+            ])
+        );
+        assert_eq!(route.matches(&"GET://acme.other.test/foo.txt".parse().unwrap()), None);
+        assert_eq!(route.matches(&"GET://a.b.example.test/foo.txt".parse().unwrap()), None);
+        assert_eq!(route.matches(&"GET:/foo.txt".parse().unwrap()), None);
+
+        let route = Route::from_str("GET://{sub..}.example.test/ handler.sh ${sub}").unwrap();
+        assert_eq!(
+            route.matches(&"GET://a.b.example.test/".parse().unwrap()),
+            Some(vec![(&String::from("sub"), String::from("a.b"))])
+        );
+        assert_eq!(route.matches(&"GET://example.test/".parse().unwrap()), None);
+        assert_eq!(route.matches(&"GET://...example.test/".parse().unwrap()), None);
+        assert_eq!(route.matches(&"GET://a..b.example.test/".parse().unwrap()), None);
+
+        assert!(Route::from_str("GET://example.{tld..}/ handler.sh").is_err());
+    }
+
+    #[test]
+    fn test_route_canonical_path() {
+        let route = Route::from_str("GET:/docs/ handler.sh").unwrap();
+        assert_eq!(route.canonical_path("/docs"), "/docs/");
+        assert_eq!(route.canonical_path("//docs//"), "/docs/");
+
+        let route = Route::from_str("GET:/foo/{id} handler.sh").unwrap();
+        assert_eq!(route.canonical_path("/foo/1/"), "/foo/1");
+        assert_eq!(route.canonical_path("/foo//1"), "/foo/1");
+
+        let route = Route::from_str("GET:/files/{path..} handler.sh").unwrap();
+        assert_eq!(route.canonical_path("/files//a/"), "/files/a/");
+        assert_eq!(route.canonical_path("/files/a"), "/files/a");
+
+        let route = Route::from_str("GET:/ handler.sh").unwrap();
+        assert_eq!(route.canonical_path("//"), "/");
+    }
+
+    #[test]
+    fn test_route_options_allows_client() {
+        let options = RouteOptions { peer_uid: Some(vec![1000]), ..Default::default() };
+        assert!(options.has_client_conditions());
+
+        let peer = |uid| ConnectionInfo {
+            peer: Some(crate::connection::PeerCred { uid, gid: 100, pid: None }),
+            ..Default::default()
+        };
+        assert!(options.allows_client(&peer(1000)));
+        assert!(!options.allows_client(&peer(0)));
+        assert!(!options.allows_client(&ConnectionInfo::default()));
+
+        assert!(RouteOptions::default().allows_client(&ConnectionInfo::default()));
+
+        let options = RouteOptions { client_ip: Some(vec!["192.0.2.0/24".parse().unwrap()]), ..Default::default() };
+        let remote = |addr: &str| ConnectionInfo {
+            remote: Some(addr.parse::<std::net::SocketAddr>().unwrap().into()),
+            ..Default::default()
+        };
+        assert!(options.allows_client(&remote("192.0.2.7:4000")));
+        assert!(!options.allows_client(&remote("198.51.100.7:4000")));
+        assert!(!options.allows_client(&ConnectionInfo::default()));
+    }
+}
@@ -1,63 +1,253 @@
 use crate::Error;
-use std::{io::Read, os::fd::OwnedFd};
+use nix::{
+    sys::signal::{killpg, Signal},
+    unistd::{setpgid, Pid}
+};
+use std::{
+    any::Any, collections::HashSet, io::Cursor, os::fd::{AsRawFd, OwnedFd}, process::{ExitStatus, Stdio},
+    sync::{Arc, LazyLock, Mutex}, time::Duration
+};
 use super::RouteResponse;
 use hyper::StatusCode;
-use tokio::{io, process::Child};
+use tokio::{
+    io::{self, AsyncReadExt},
+    net::unix::pipe,
+    process::{ChildStderr, ChildStdin, ChildStdout, Command},
+    sync::{oneshot, watch}
+};
+
+/// Exit code handlers use to pass the request on to the next matching route,
+/// like writing a `Pass` header
+pub const PASS_EXIT_CODE: i32 = 100;
+
+/// Output buffered while the handler runs, before the response starts anyway
+const MAX_BUFFERED_OUTPUT: usize = 64 * 1024;
+
+/// Pause in the output of a running handler, after which the response starts
+const STREAM_DELAY: Duration = Duration::from_millis(100);
+
+/// Process groups of running handlers, each handler leads its own
+static RUNNING: LazyLock<watch::Sender<HashSet<i32>>> = LazyLock::new(|| watch::Sender::new(HashSet::new()));
 
 pub struct RouteProcess {
-    child: Child,
-    read_pipe: os_pipe::PipeReader,
+    pid: i32,
+    stdin: Option<ChildStdin>,
+    stdout: Option<ChildStdout>,
+    /// Kept open, so handlers writing to stderr don't fail
+    _stderr: Option<ChildStderr>,
+    /// Exit status, from the task that waits on the handler, which keeps
+    /// running after the response started
+    exit: oneshot::Receiver<io::Result<ExitStatus>>,
+    /// Dropped by the wait task once the handler exits
+    exit_guards: Arc<Mutex<Vec<Box<dyn Any + Send>>>>,
+    read_pipe: pipe::Receiver,
     write_pipe_fd: Option<OwnedFd>
 }
 
+/// What ended the wait for the response to start
+enum WaitEvent {
+    Exited(ExitStatus),
+    Output(usize),
+    Streaming
+}
+
 impl RouteProcess {
-    pub fn new(child: Child, read_pipe: os_pipe::PipeReader, write_pipe_fd: OwnedFd) -> Self {
-        RouteProcess { child, read_pipe, write_pipe_fd: Some(write_pipe_fd) }
+    /// Spawn handler command with piped stdio and header pipe, in its own
+    /// process group
+    pub fn spawn(mut cmd: Command) -> Result<Self, Error> {
+        let (read_pipe, write_pipe) = os_pipe::pipe()?;
+        let read_pipe = pipe::Receiver::from_owned_fd(read_pipe.into())?;
+        let write_pipe_fd: OwnedFd = write_pipe.into();
+
+        // FIXME could this be made cross platform, or at least work on MacOS?
+        let write_pipe_path = format!("/proc/{}/fd/{}",
+            std::process::id(),
+            write_pipe_fd.as_raw_fd()
+        );
+
+        cmd.env("SHELL_SERVE_PIPE", write_pipe_path);
+
+        // so shutdown can signal the handler along with its own children
+        unsafe {
+            cmd.pre_exec(|| setpgid(Pid::from_raw(0), Pid::from_raw(0)).map_err(io::Error::from));
+        }
+
+        let mut child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(Error::RouteSpawn)?;
+
+        let pid = child.id().expect("spawned handler should have a pid") as i32;
+        RUNNING.send_modify(|running| {
+            running.insert(pid);
+        });
+
+        let (exit_sender, exit) = oneshot::channel();
+        let (stdin, stdout, stderr) = (child.stdin.take(), child.stdout.take(), child.stderr.take());
+        let exit_guards = Arc::new(Mutex::new(vec![]));
+
+        let guards = exit_guards.clone();
+        tokio::spawn(async move {
+            let status = child.wait().await;
+            guards.lock().unwrap().clear();
+            RUNNING.send_modify(|running| {
+                running.remove(&pid);
+            });
+            let _ = exit_sender.send(status);
+        });
+
+        Ok(RouteProcess {
+            pid,
+            stdin,
+            stdout,
+            _stderr: stderr,
+            exit,
+            exit_guards,
+            read_pipe,
+            write_pipe_fd: Some(write_pipe_fd)
+        })
+    }
+
+    /// Keep `guard` until the handler exits, which may be after the response
+    /// started, e.g. a temp file the handler reads
+    pub fn keep_until_exit<T: Send + 'static>(&self, guard: T) {
+        self.exit_guards.lock().unwrap().push(Box::new(guard));
+    }
+
+    /// Number of handlers still running, of all routers
+    pub fn running() -> usize {
+        RUNNING.borrow().len()
+    }
+
+    /// Wait until no handlers are running
+    pub async fn all_exited() {
+        let mut running = RUNNING.subscribe();
+        // can't fail, the sender is static
+        let _ = running.wait_for(HashSet::is_empty).await;
+    }
+
+    /// Send `signal` to the process groups of all running handlers
+    pub fn signal_all(signal: Signal) {
+        for pgid in RUNNING.borrow().iter() {
+            if let Err(e) = killpg(Pid::from_raw(*pgid), signal) {
+                println!("Failed to send {signal} to handler {pgid}: {e}");
+            }
+        }
     }
 
     pub async fn load_stdin<S>(&mut self, reader: &mut S) -> Result<&mut Self, Error>
         where S: io::AsyncRead + Unpin
     {
-        let mut stdin = self.child.stdin.take()
+        let mut stdin = self.stdin.take()
             .ok_or(Error::RouteIoOpen)?;
 
-        io::copy(reader, &mut stdin).await?;
+        match io::copy(reader, &mut stdin).await {
+            // handler exited, or closed stdin without reading the whole body
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(self),
+            Err(e) => Err(e.into()),
+            Ok(_) => Ok(self)
+        }
+    }
+
+    /// Kill the process, e.g. after failing to send the request body
+    pub async fn kill(&mut self) -> Result<(), Error> {
+        if RUNNING.borrow().contains(&self.pid) {
+            killpg(Pid::from_raw(self.pid), Signal::SIGKILL)
+                .map_err(|e| Error::RouteWait(e.into()))?;
+        }
+
+        self.exited().await.map(|_| ())
+    }
 
-        Ok(self)
+    async fn exited(&mut self) -> Result<ExitStatus, Error> {
+        match (&mut self.exit).await {
+            Ok(status) => status.map_err(Error::RouteWait),
+            Err(_) => Err(Error::RouteWait(io::Error::other("handler wait task ended")))
+        }
     }
 
-    pub async fn wait(&mut self) -> Result<RouteResponse, Error> {
-        let status = self.child.wait().await
-            .map_err(|e| Error::RouteWait(e))?;
+    /// Wait until the response can start: when the handler exits, or when it
+    /// wrote more output than is buffered. With `stream`, also when it keeps
+    /// running after writing output. Only an exited handler's exit code can
+    /// set the status.
+    pub async fn wait(&mut self, stream: bool) -> Result<RouteResponse, Error> {
+        // handlers reading stdin see the end of a request without a body
+        drop(self.stdin.take());
+
+        let mut stdout = self.stdout.take()
+            .ok_or(Error::RouteIoOpen)?;
+
+        let mut head = vec![];
+        let mut buf = vec![0; 8 * 1024];
+        let mut stdout_open = true;
 
-        // close writer side of pipe to avoid blocking reader
-        let write_pipe_fd = self.write_pipe_fd.take()
-            .expect("write_pipe_fd should be set");
-        drop(write_pipe_fd);
+        let exit_status = loop {
+            let streaming = stream && stdout_open && !head.is_empty();
 
-        let mut pipe_buf = String::new();
-        self.read_pipe.read_to_string(&mut pipe_buf)?;
+            let event = tokio::select! {
+                status = self.exited() => WaitEvent::Exited(status?),
+                len = stdout.read(&mut buf), if stdout_open => WaitEvent::Output(len?),
+                _ = tokio::time::sleep(STREAM_DELAY), if streaming => WaitEvent::Streaming
+            };
 
+            match event {
+                WaitEvent::Exited(status) => break Some(status),
+                WaitEvent::Output(0) => stdout_open = false,
+                WaitEvent::Output(len) => {
+                    head.extend_from_slice(&buf[..len]);
+                    if head.len() >= MAX_BUFFERED_OUTPUT {
+                        break None;
+                    }
+                },
+                WaitEvent::Streaming => break None
+            }
+        };
+
+        let mut pipe_buf = vec![];
+        if exit_status.is_some() {
+            // close writer side of pipe to avoid blocking reader
+            let write_pipe_fd = self.write_pipe_fd.take()
+                .expect("write_pipe_fd should be set");
+            drop(write_pipe_fd);
+
+            self.read_pipe.read_to_end(&mut pipe_buf).await?;
+        } else {
+            // headers are written before the output, so they're in the pipe
+            loop {
+                match self.read_pipe.try_read_buf(&mut pipe_buf) {
+                    Ok(0) => break,
+                    Ok(_) => (),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e.into())
+                }
+            }
+        }
+
+        let pipe_buf = String::from_utf8_lossy(&pipe_buf);
         let headers = pipe_buf.lines()
             .map(parse_header)
             .collect::<Result<Vec<_>, _>>()?;
 
+        let passed = exit_status.is_some_and(|s| s.code() == Some(PASS_EXIT_CODE))
+            || headers.iter().any(|(k, _)| k.eq_ignore_ascii_case("pass"));
+
         let status = match headers.iter().find(|(k, _)| k == "Status") {
             // use status header read from pipe
             Some((_, status)) => StatusCode::from_u16(
                 status.parse().map_err(|_| Error::InvalidStatus(status.to_string()))?
             ).map_err(|_| Error::InvalidStatus(status.to_string()))?,
             // or derive status from process exit code
-            None => match status.success() {
+            None => match exit_status.is_none_or(|s| s.success()) {
                 true => StatusCode::OK,
                 false => StatusCode::INTERNAL_SERVER_ERROR
             }
         };
 
-        let stdout = self.child.stdout.take()
-            .ok_or(Error::RouteIoOpen)?;
+        let stdout = Box::pin(Cursor::new(head).chain(stdout));
 
-        Ok(RouteResponse { status, headers, stdout })
+        Ok(RouteResponse { status, headers, stdout, passed })
     }
 }
 
@@ -70,4 +260,80 @@ fn parse_header(line: &str) -> Result<(String, String), Error> {
         &[name, value, ..] => Ok((name.to_owned(), value.to_owned())),
         _ => Err(Error::InvalidHeader(line.to_owned()))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    /// Process exists and isn't a zombie waiting to be reaped
+    fn alive(pid: i32) -> bool {
+        std::fs::read_to_string(format!("/proc/{pid}/stat"))
+            .is_ok_and(|stat| stat.rsplit(") ").next().is_some_and(|s| !s.starts_with('Z')))
+    }
+
+    /// Spawn `sh -c script`, with a background `sleep` whose pid it writes
+    /// to `pid_file`, and wait for the pid
+    async fn spawn_with_child(pid_file: &Path) -> (RouteProcess, i32) {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(format!("sleep 30 & echo $! > {}; wait", pid_file.display()));
+        let process = RouteProcess::spawn(cmd).unwrap();
+
+        loop {
+            if let Some(pid) = std::fs::read_to_string(pid_file).ok().and_then(|p| p.trim().parse().ok()) {
+                return (process, pid);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_kill_process_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut process, child) = spawn_with_child(&dir.path().join("pid")).await;
+        assert!(alive(child));
+
+        process.kill().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!alive(child));
+    }
+
+    #[tokio::test]
+    async fn test_signal_all() {
+        // SIGWINCH is ignored by default, so handlers of other tests keep running
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("trap 'exit 3' WINCH; echo started; while :; do sleep 0.05; done");
+        let mut process = RouteProcess::spawn(cmd).unwrap();
+        let mut started = [0; 8];
+        process.stdout.as_mut().unwrap().read_exact(&mut started).await.unwrap();
+        assert!(RouteProcess::running() >= 1);
+
+        RouteProcess::signal_all(Signal::SIGWINCH);
+        assert_eq!(process.exited().await.unwrap().code(), Some(3));
+        assert!(!RUNNING.borrow().contains(&process.pid));
+
+        tokio::time::timeout(Duration::from_secs(10), RouteProcess::all_exited()).await
+            .expect("all handlers should have exited");
+    }
+
+    #[tokio::test]
+    async fn test_keep_until_exit() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        let path = file.path().to_path_buf();
+
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("echo started; sleep 0.3");
+        let mut process = RouteProcess::spawn(cmd).unwrap();
+        process.keep_until_exit(file);
+
+        // streamed response started, and the process dropped, while it runs
+        let response = process.wait(true).await.unwrap();
+        drop(process);
+        assert!(path.exists());
+
+        drop(response);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert!(!path.exists());
+    }
+}
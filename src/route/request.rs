@@ -1,12 +1,72 @@
-use crate::Error;
+use crate::{connection::ConnectionInfo, Error};
 use std::{collections::HashMap, path::PathBuf, str::FromStr};
 use super::Method;
 
+#[derive(Clone)]
 pub struct RouteRequest {
     pub method: Method,
+    /// Host name without port, in lower case
+    pub host: Option<String>,
     pub path: PathBuf,
     pub query: HashMap<String, String>,
-    pub headers: HashMap<String, String>
+    pub headers: HashMap<String, String>,
+    /// Parsed request body, when read ahead for routes matching on the body
+    pub body: Option<BodyFields>,
+    /// Connection the request came in on
+    pub connection: ConnectionInfo
+}
+
+/// Form fields or JSON document from the request body
+#[derive(Debug, Clone)]
+pub enum BodyFields {
+    Form(HashMap<String, String>),
+    Json(serde_json::Value)
+}
+
+impl BodyFields {
+    /// Parse body for supported content types, `None` when content type isn't
+    /// supported or body is malformed
+    pub fn parse(content_type: &str, body: &[u8]) -> Option<Self> {
+        let mime = content_type.split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        if mime == "application/x-www-form-urlencoded" {
+            let body = std::str::from_utf8(body).ok()?;
+            let fields = urlparse::parse_qs(body).into_iter()
+                .map(|(key, val)| (key, val.join(",")))
+                .collect();
+            Some(BodyFields::Form(fields))
+        } else if mime == "application/json" || mime.ends_with("+json") {
+            serde_json::from_slice(body).ok()
+                .map(BodyFields::Json)
+        } else {
+            None
+        }
+    }
+
+    /// Value of `.name` dotted path or `/name` JSON pointer field, JSON values
+    /// other than strings are formatted as JSON
+    pub fn get(&self, field: &str) -> Option<String> {
+        match self {
+            BodyFields::Form(fields) => fields.get(&field[1..]).cloned(),
+            BodyFields::Json(value) => {
+                let pointer = if field.starts_with('/') {
+                    field.to_string()
+                } else {
+                    field.replace('.', "/")
+                };
+
+                value.pointer(&pointer).map(|v| match v {
+                    serde_json::Value::String(s) => s.clone(),
+                    serde_json::Value::Null => String::new(),
+                    v => v.to_string()
+                })
+            }
+        }
+    }
 }
 
 impl FromStr for RouteRequest {
@@ -25,11 +85,17 @@ impl FromStr for RouteRequest {
             HashMap::new()
         };
 
+        let host = Some(path_uri.netloc)
+            .filter(|h| !h.is_empty());
+
         Ok(RouteRequest {
             method: Method::from_str(method)?,
+            host,
             path: PathBuf::from(path_uri.path),
             query,
-            headers: HashMap::new()
+            headers: HashMap::new(),
+            body: None,
+            connection: ConnectionInfo::default()
         })
     }
 }
\ No newline at end of file
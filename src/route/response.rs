@@ -1,8 +1,12 @@
 use hyper::StatusCode;
-use tokio::process::ChildStdout;
+use std::pin::Pin;
+use tokio::io::AsyncRead;
 
 pub struct RouteResponse {
     pub status: StatusCode,
     pub headers: Vec<(String, String)>,
-    pub stdout: ChildStdout
-}
\ No newline at end of file
+    /// Output read while waiting for the response to start, then the rest
+    pub stdout: Pin<Box<dyn AsyncRead + Send + Sync>>,
+    /// Handler declined the request, so the next matching route should try
+    pub passed: bool
+}
@@ -1,35 +1,256 @@
-use crate::route::{Route, RouteProcess, RouteRequest};
+use crate::{
+    error_page::ErrorPages,
+    negotiate,
+    proxy::TrustedProxies,
+    rewrite::RewriteRule,
+    route::{Route, RouteParams, RouteProcess, RouteRequest, TrailingSlash}
+};
+use hyper::{header, StatusCode};
+use std::{path::PathBuf, sync::{Arc, RwLock}, time::Duration};
 
 
+#[derive(Clone, Default)]
+pub struct RouterConfig {
+    /// Include internal error details in problem responses
+    pub debug: bool,
+    /// Handlers or files that produce error response bodies
+    pub error_pages: ErrorPages,
+    /// Request body size limit in bytes, unless overridden by the route
+    pub max_body_size: Option<u64>,
+    /// Time for handlers to start their response
+    pub handler_timeout: Option<Duration>,
+    /// Where request bodies are spooled, defaults to the system temp dir
+    pub temp_dir: Option<PathBuf>,
+    /// Host used for matching when request host doesn't match any route host
+    pub default_host: Option<String>,
+    /// Rules applied to the request path before routes are matched
+    pub rewrites: Vec<RewriteRule>,
+    /// Handling of trailing and repeated slashes, unless overridden by the route
+    pub trailing_slash: TrailingSlash,
+    /// Proxies whose `Forwarded` or `X-Forwarded-For` header, whichever they're
+    /// configured to set, is used for the client address
+    pub trusted_proxies: TrustedProxies
+}
+
+/// Router shared by connections. Its route table can be replaced at any
+/// time, requests keep the table they started with.
 #[derive(Clone)]
 pub struct ShellRouter {
-    routes: Vec<Route>
+    table: Arc<RwLock<Arc<RouteTable>>>
 }
 
 impl ShellRouter {
-    pub fn new(routes: Vec<Route>) -> Self {
-        Self { routes }
+    pub fn new(routes: Vec<Route>, config: RouterConfig) -> Self {
+        Self { table: Arc::new(RwLock::new(Arc::new(RouteTable { routes, config }))) }
     }
 
-    pub fn execute(&self, req: &RouteRequest) -> Result<RouteProcess, RouterError> {
-        let match_result = self.routes.iter().find_map(|r| {
-            r.matches(&req).map(|m| (r, m))
-        });
+    /// Current route table
+    pub fn table(&self) -> Arc<RouteTable> {
+        self.table.read().unwrap().clone()
+    }
+
+    /// Swap in new routes and config, for requests from now on
+    pub fn replace(&self, routes: Vec<Route>, config: RouterConfig) {
+        *self.table.write().unwrap() = Arc::new(RouteTable { routes, config });
+    }
+}
+
+/// Routes and settings that requests are handled with
+pub struct RouteTable {
+    routes: Vec<Route>,
+    pub(crate) config: RouterConfig
+}
+
+impl RouteTable {
+    pub fn routes(&self) -> &[Route] {
+        &self.routes
+    }
+
+    pub fn config(&self) -> &RouterConfig {
+        &self.config
+    }
+
+    /// Find route matching request, and it's captured params. When routes
+    /// have `accept` conditions, the matching route with the best quality for
+    /// the request `Accept` header is chosen, otherwise the first match.
+    pub fn find(&self, req: &RouteRequest) -> Result<RouteMatch<'_>, RouterError> {
+        self.find_excluding(req, &[])
+    }
+
+    /// Find route like `find`, skipping the routes at `excluded` indexes, e.g.
+    /// routes whose handler passed the request on
+    pub fn find_excluding(&self, req: &RouteRequest, excluded: &[usize]) -> Result<RouteMatch<'_>, RouterError> {
+        let default_req;
+        let req = match &self.config.default_host {
+            Some(host) if !self.routes.iter().any(|r| r.matches_host(req)) => {
+                default_req = RouteRequest { host: Some(host.clone()), ..req.clone() };
+                &default_req
+            },
+            _ => req
+        };
+
+        let path = req.path.to_string_lossy();
+        let candidates: Vec<_> = self.routes.iter()
+            .enumerate()
+            .filter(|(i, _)| !excluded.contains(i))
+            .filter(|(_, r)| self.trailing_slash(r) != TrailingSlash::Strict || r.canonical_path(&path) == path)
+            .filter_map(|(i, r)| r.matches(req).map(|m| (i, r, m)))
+            .collect();
+
+        if candidates.is_empty() {
+            return Err(RouterError::RouteNotFound);
+        }
+
+        let candidates = if candidates.iter().any(|(_, r, _)| r.options().has_client_conditions()) {
+            let candidates: Vec<_> = candidates.into_iter()
+                .filter(|(_, r, _)| r.options().allows_client(&req.connection))
+                .collect();
+
+            if candidates.is_empty() {
+                return Err(RouterError::ClientForbidden);
+            }
+
+            candidates
+        } else {
+            candidates
+        };
+
+        let mut vary = vec![];
+
+        let candidates = if candidates.iter().any(|(_, r, _)| r.options().content_type.is_some()) {
+            vary.push("Content-Type");
+
+            let content_type = req.headers.get("content-type")
+                .map(String::as_str)
+                .unwrap_or_default();
 
-        if let Some((route, params)) = match_result {
-            Ok(route.spawn(params)?)
+            let candidates: Vec<_> = candidates.into_iter()
+                .filter(|(_, r, _)| match &r.options().content_type {
+                    Some(consumes) => negotiate::content_type_matches(consumes, content_type),
+                    None => true
+                })
+                .collect();
+
+            if candidates.is_empty() {
+                return Err(RouterError::UnsupportedMediaType(content_type.to_string()));
+            }
+
+            candidates
         } else {
-            Err(RouterError::RouteNotFound)
+            candidates
+        };
+
+        if candidates.iter().any(|(_, r, _)| r.options().accept.is_some()) {
+            vary.push("Accept");
         }
+
+        let accept = req.headers.get("accept")
+            .map(|a| negotiate::parse_accept(a));
+
+        let mut best: Option<(f32, RouteMatch)> = None;
+
+        for (index, route, params) in candidates {
+            let (quality, media_type) = match &route.options().accept {
+                Some(produces) => negotiate::best_media_type(produces, accept.as_deref()),
+                None => (1.0, None)
+            };
+
+            // ties go to the route defined first
+            if quality > 0.0 && best.as_ref().is_none_or(|(q, _)| quality > *q) {
+                let media_type = media_type.filter(|t| !t.contains('*')).cloned();
+                best = Some((quality, RouteMatch { index, route, params, media_type, vary: vec![] }));
+            }
+        }
+
+        let (_, mut route_match) = best.ok_or_else(|| RouterError::NotAcceptable(vary.clone()))?;
+        route_match.vary = vary;
+
+        Ok(route_match)
+    }
+
+    /// Trailing slash policy of route, or the server wide policy
+    pub fn trailing_slash(&self, route: &Route) -> TrailingSlash {
+        route.options().trailing_slash
+            .unwrap_or(self.config.trailing_slash)
+    }
+
+    /// Any route matches on request body fields
+    pub fn matches_body(&self) -> bool {
+        self.routes.iter().any(Route::matches_body)
+    }
+
+    pub fn execute(&self, req: &RouteRequest) -> Result<RouteProcess, RouterError> {
+        let route_match = self.find(req)?;
+        Ok(route_match.route.spawn(route_match.params)?)
     }
 }
 
+/// Route chosen for a request
+pub struct RouteMatch<'a> {
+    /// Index of route in the router
+    pub index: usize,
+    pub route: &'a Route,
+    pub params: RouteParams<'a>,
+    /// Negotiated response media type, from the route `accept` types
+    pub media_type: Option<String>,
+    /// Request headers that affected the choice of route
+    pub vary: Vec<&'static str>
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum RouterError {
     #[error("No matching route found")]
     RouteNotFound,
-    #[error("Route command failed to spawn")]
-    RouteSpawnFailed(#[from] crate::Error),
+    #[error("Route handler failed")]
+    RouteFailed(#[from] crate::Error),
     #[error("Unsupported method '{0}'")]
-    UnsupportedMethod(String)
+    UnsupportedMethod(String),
+    #[error("Method '{0}' not allowed")]
+    MethodNotAllowed(String, &'static str),
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Request body exceeds limit of {0} bytes")]
+    PayloadTooLarge(u64),
+    #[error("Multipart body exceeds limit of {0} parts")]
+    TooManyParts(usize),
+    #[error("Unsupported media type '{0}'")]
+    UnsupportedMediaType(String),
+    /// Request headers that ruled out the routes, for `Vary`
+    #[error("No acceptable media type")]
+    NotAcceptable(Vec<&'static str>),
+    #[error("File not found: {0}")]
+    FileNotFound(String),
+    #[error("Client not allowed")]
+    ClientForbidden
+}
+
+impl RouterError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RouterError::RouteNotFound
+                | RouterError::FileNotFound(_) => StatusCode::NOT_FOUND,
+            RouterError::UnsupportedMethod(_)
+                | RouterError::MethodNotAllowed(..) => StatusCode::METHOD_NOT_ALLOWED,
+            RouterError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+            RouterError::PayloadTooLarge(_)
+                | RouterError::TooManyParts(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            RouterError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            RouterError::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            RouterError::ClientForbidden => StatusCode::FORBIDDEN,
+            RouterError::RouteFailed(e) => e.status()
+        }
+    }
+
+    /// Headers sent with the error response, `Vary` for the request headers
+    /// a different value of which could avoid the error and `Allow`
+    pub fn headers(&self) -> Vec<(String, String)> {
+        let vary = match self {
+            RouterError::UnsupportedMediaType(_) => "Content-Type".to_string(),
+            RouterError::NotAcceptable(vary) if !vary.is_empty() => vary.join(", "),
+            RouterError::MethodNotAllowed(_, allow) => return vec![(header::ALLOW.to_string(), allow.to_string())],
+            _ => return vec![]
+        };
+
+        vec![(header::VARY.to_string(), vary)]
+    }
 }
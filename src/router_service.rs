@@ -1,80 +1,614 @@
 use crate::{
-    route::{Method, RouteRequest, RouteResponse},
-    router::{RouterError, ShellRouter}
+    connection::ConnectionInfo,
+    error_page::{file_content_type, ErrorContext, ErrorPage},
+    problem::{self, error_chain, Problem},
+    multipart::spool_multipart,
+    request_body::{body_too_large, spool_to_file, RequestBody},
+    rewrite::{apply_rewrites, rewrite_request},
+    route::{
+        expand_params, BodyFields, BodyMode, Method, RouteAction, RouteProcess, RouteRequest, TrailingSlash
+    },
+    router::{RouteMatch, RouteTable, RouterError, ShellRouter},
+    static_files::send_file,
+    Error
 };
 use futures_util::TryStreamExt;
-use http_body_util::{combinators::BoxBody, BodyExt, BodyStream, Empty, StreamBody};
-use hyper::{body, body::Body, StatusCode, Request, Response};
-use std::{collections::HashMap, io::Error as IoError, str::FromStr, path::PathBuf};
-use tokio::io::AsyncRead;
-use tokio_util::io::{ReaderStream, StreamReader};
+use http_body_util::{combinators::BoxBody, BodyExt, Full, StreamBody};
+use hyper::{body, header, http::request, Request, Response, StatusCode};
+use std::{
+    collections::HashMap, convert::Infallible, error::Error as StdError, ffi::OsString, io::{Cursor, Error as IoError}, path::PathBuf,
+    str::FromStr, sync::atomic::{AtomicU64, Ordering}
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_util::io::ReaderStream;
+
+
+pub(crate) type ResponseBody = BoxBody<body::Bytes, IoError>;
+pub(crate) type ServiceResponse = Response<ResponseBody>;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
 
+/// Limit on internal rewrites of one request, to break rewrite loops
+const MAX_REWRITES: usize = 10;
 
-type ServiceResponse = Response<BoxBody<body::Bytes, IoError>>;
+/// Limit on handlers tried for one request, when they pass it on
+const MAX_PASSES: usize = 10;
+
+/// Request bodies larger than this can't be matched by route body fields
+const MAX_BODY_MATCH_SIZE: usize = 1024 * 1024;
 
 impl ShellRouter {
-    pub async fn call(&self, req: Request<body::Incoming>) -> Result<ServiceResponse, RouterError> {
-        let result = self._call(req).await;
-        match result {
+    /// Handle request with the current route table
+    pub async fn call<B>(&self, req: Request<B>) -> Result<ServiceResponse, Infallible>
+        where B: body::Body<Data = body::Bytes> + Send + 'static,
+              B::Error: Into<Box<dyn StdError + Send + Sync>>
+    {
+        self.table().call(req).await
+    }
+}
+
+impl RouteTable {
+    pub async fn call<B>(&self, mut req: Request<B>) -> Result<ServiceResponse, Infallible>
+        where B: body::Body<Data = body::Bytes> + Send + 'static,
+              B::Error: Into<Box<dyn StdError + Send + Sync>>
+    {
+        // behind trusted proxies the client is the one they forwarded for
+        let conn = req.extensions().get::<ConnectionInfo>()
+            .filter(|conn| conn.remote.is_some())
+            .map(|conn| ConnectionInfo {
+                remote: conn.remote.map(|remote| self.config.trusted_proxies.client(remote, req.headers())),
+                ..conn.clone()
+            });
+        if let Some(conn) = conn {
+            req.extensions_mut().insert(conn);
+        }
+
+        let info = RequestInfo::new(&req);
+
+        match self._call(req, &info).await {
             Ok(response) => Ok(response),
-            Err(RouterError::UnsupportedMethod(_)) => {
-                Ok(empty_response(StatusCode::METHOD_NOT_ALLOWED))
-            },
-            Err(RouterError::RouteNotFound) => {
-                Ok(empty_response(StatusCode::NOT_FOUND))
+            Err(e) => {
+                let reason = error_chain(&e);
+                println!("[{}] {} {}: {reason}", info.request_id, info.method, info.path);
+
+                let page = self.error_page_response(&info, e.status(), reason, e.headers())
+                    .await;
+                Ok(page.unwrap_or_else(|| self.problem_response(&e, &info.request_id)))
+            }
+        }
+    }
+
+    fn problem_response(&self, err: &RouterError, request_id: &str) -> ServiceResponse {
+        let status = err.status();
+        let problem = Problem::new(status, err, request_id, self.config.debug);
+
+        let mut builder = Response::builder()
+            .status(status)
+            .header(header::CONTENT_TYPE, problem::CONTENT_TYPE)
+            .header(REQUEST_ID_HEADER, request_id);
+
+        for (name, value) in err.headers() {
+            builder = builder.header(name, value);
+        }
+
+        builder.body(full_body(problem.to_json()))
+            .unwrap()
+    }
+
+    /// Render configured error page for `status`, or `None` if there is no
+    /// error page or it failed
+    async fn error_page_response(
+        &self,
+        info: &RequestInfo,
+        status: StatusCode,
+        reason: String,
+        headers: Vec<(String, String)>
+    ) -> Option<ServiceResponse> {
+        let page = self.config.error_pages.find(status)?;
+
+        let ctx = ErrorContext {
+            status,
+            reason,
+            method: &info.method,
+            path: &info.path,
+            request_id: &info.request_id
+        };
+
+        let result = match render_error_page(page, &ctx).await {
+            Ok((page_headers, body)) => {
+                let mut builder = Response::builder()
+                    .status(status)
+                    .header(REQUEST_ID_HEADER, &info.request_id);
+
+                // keep headers from failed handler, e.g. `WWW-Authenticate`
+                for (name, value) in headers.into_iter().chain(page_headers) {
+                    if name.eq_ignore_ascii_case("status") {
+                        continue;
+                    }
+                    builder = builder.header(name, value);
+                }
+
+                builder.body(body)
+                    .map_err(|e| Error::InvalidHeader(e.to_string()))
             },
             Err(e) => Err(e)
+        };
+
+        match result {
+            Ok(response) => Some(response),
+            Err(e) => {
+                println!("[{}] error page for {status} failed: {}", info.request_id, error_chain(&e));
+                None
+            }
         }
     }
 
-    async fn _call(&self, req: Request<body::Incoming>) -> Result<ServiceResponse, RouterError> {
-        let route_req = to_route_req(&req)?;
+    async fn _call<B>(&self, req: Request<B>, info: &RequestInfo) -> Result<ServiceResponse, RouterError>
+        where B: body::Body<Data = body::Bytes> + Send + 'static,
+              B::Error: Into<Box<dyn StdError + Send + Sync>>
+    {
+        let (parts, body) = req.into_parts();
+        let mut route_req = to_route_req(&parts)?;
+        let mut body = RequestBody::new(body);
+
+        if !self.config.rewrites.is_empty() {
+            let mut query = parts.uri.query().map(String::from);
+            let redirect = apply_rewrites(&self.config.rewrites, &mut route_req, &mut query)?;
+
+            if let Some(status) = redirect {
+                let mut location = route_req.path.to_string_lossy().to_string();
+                if let Some(query) = query {
+                    location = format!("{location}?{query}");
+                }
+
+                return Ok(Response::builder()
+                    .status(status)
+                    .header(header::LOCATION, location)
+                    .body(full_body(""))
+                    .map_err(|e| Error::InvalidHeader(e.to_string()))?);
+            }
+        }
+
+        if self.matches_body() {
+            if let Some(content_type) = route_req.headers.get("content-type") {
+                let limit = self.config.max_body_size
+                    .map_or(MAX_BODY_MATCH_SIZE, |l| MAX_BODY_MATCH_SIZE.min(l as usize));
+
+                if let Some(data) = body.read_ahead(limit).await.map_err(Error::from)? {
+                    route_req.body = BodyFields::parse(content_type, data);
+                }
+            }
+        }
+
+        // routes whose handler passed the request on
+        let mut passed = vec![];
+
+        loop {
+            let mut rewrites = 0;
+            let route_match = loop {
+                let route_match = self.find_excluding(&route_req, &passed)?;
+
+                let RouteAction::Rewrite(target) = route_match.route.action() else {
+                    break route_match;
+                };
+
+                if rewrites == MAX_REWRITES {
+                    return Err(Error::InvalidRoute(format!("more than {MAX_REWRITES} rewrites")).into());
+                }
+                rewrites += 1;
+
+                rewrite_request(&mut route_req, &expand_params(target, &route_match.params))?;
+            };
+
+            let route = route_match.route;
+            let params = &route_match.params;
+
+            let is_static = matches!(route.action(), RouteAction::Static(_));
+            if parts.method == hyper::Method::HEAD && !is_static {
+                return Err(RouterError::UnsupportedMethod(parts.method.to_string()));
+            }
+
+            // redirect to canonical path, unless it was rewritten internally
+            if self.trailing_slash(route) == TrailingSlash::Redirect && rewrites == 0 && passed.is_empty() {
+                let path = parts.uri.path();
+                let canonical = route.canonical_path(path);
+
+                if canonical != path && route_req.path.as_os_str() == path {
+                    let location = match parts.uri.query() {
+                        Some(query) => format!("{canonical}?{query}"),
+                        None => canonical
+                    };
+
+                    return Ok(Response::builder()
+                        .status(StatusCode::PERMANENT_REDIRECT)
+                        .header(header::LOCATION, location)
+                        .body(full_body(""))
+                        .unwrap());
+                }
+            }
+
+            let builtin_response = match route.action() {
+                RouteAction::Handler(_) | RouteAction::Rewrite(_) => None,
+                RouteAction::Static(dir) => {
+                    if !matches!(parts.method, hyper::Method::GET | hyper::Method::HEAD) {
+                        return Err(RouterError::MethodNotAllowed(parts.method.to_string(), "GET, HEAD"));
+                    }
+
+                    let file_path = match route.path_catch_all() {
+                        Some(name) => params.iter()
+                            .find(|(k, _)| *k == name)
+                            .map_or(String::new(), |(_, v)| v.clone()),
+                        None => route_req.path.to_string_lossy().to_string()
+                    };
+
+                    let response = dir.serve(&file_path, params, &parts).await?;
+
+                    // same headers as for GET, including `Content-Length`
+                    if parts.method == hyper::Method::HEAD {
+                        let (head, _) = response.into_parts();
+                        Some(Response::from_parts(head, full_body("")))
+                    } else {
+                        Some(response)
+                    }
+                },
+                RouteAction::Respond(fixed) => Some(fixed.response()?),
+                RouteAction::Redirect(redirect) => {
+                    let path_and_query = parts.uri.path_and_query()
+                        .map_or("/", |p| p.as_str());
+                    let authority = parts.uri.authority()
+                        .map(|a| a.as_str())
+                        .or_else(|| parts.headers.get(header::HOST)?.to_str().ok());
+                    let tls = parts.extensions.get::<ConnectionInfo>()
+                        .is_some_and(ConnectionInfo::is_tls);
+
+                    match redirect.location(params, authority, path_and_query, tls) {
+                        Some(location) => Some(redirect.response(&location)?),
+                        None => {
+                            // already where it would redirect to, so the
+                            // route doesn't apply
+                            passed.push(route_match.index);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            if let Some(mut response) = builtin_response {
+                if !route_match.vary.is_empty() {
+                    response.headers_mut()
+                        .insert(header::VARY, route_match.vary.join(", ").parse().unwrap());
+                }
+                return Ok(response);
+            }
+
+            // keep a copy of the body to replay, when the handler may pass
+            // the request on to another route
+            let mut next = passed.clone();
+            next.push(route_match.index);
+
+            let may_pass = route.options().may_pass
+                && next.len() < MAX_PASSES
+                && self.find_excluding(&route_req, &next).is_ok();
+
+            let replay = if may_pass {
+                let limit = self.config.max_body_size
+                    .map_or(MAX_BODY_MATCH_SIZE, |l| MAX_BODY_MATCH_SIZE.min(l as usize));
+                body.read_ahead(limit).await.map_err(Error::from)?;
+                body.try_clone()
+            } else {
+                None
+            };
+
+            let index = route_match.index;
+            if let Some(response) = self.run_handler(route_match, &route_req, &parts, body, info).await? {
+                return Ok(response);
+            }
 
-        let mut proc = self.execute(&route_req)?;
+            passed.push(index);
+            if passed.len() == MAX_PASSES {
+                return Err(Error::InvalidPass(format!("limit of {MAX_PASSES} passes reached")).into());
+            }
 
-        let proc = if req.body().size_hint().upper().unwrap_or(0) > 0 {
-            let stream_reader = body_stream_reader(req.into_body());
+            body = match replay {
+                Some(body) => body,
+                None => {
+                    // no route left to pass to is a plain 404
+                    self.find_excluding(&route_req, &passed)?;
+                    return Err(Error::InvalidPass("request body is too large to replay".to_string()).into());
+                }
+            };
+        }
+    }
+
+    /// Run handler of matched route, or `None` if the handler passed the request
+    async fn run_handler(
+        &self,
+        route_match: RouteMatch<'_>,
+        route_req: &RouteRequest,
+        parts: &request::Parts,
+        body: RequestBody,
+        info: &RequestInfo
+    ) -> Result<Option<ServiceResponse>, RouterError> {
+        let RouteMatch { route, mut params, media_type, vary, .. } = route_match;
+
+        let max_body_size = route.options().max_body_size
+            .or(self.config.max_body_size);
+
+        // reject up front when content-length is known to exceed limit
+        if let Some(limit) = max_body_size {
+            if body.size_hint() > limit {
+                return Err(RouterError::PayloadTooLarge(limit));
+            }
+        }
+
+        let mut env = vec![];
+        if let Some(conn) = parts.extensions.get::<ConnectionInfo>() {
+            env.extend(conn.env().into_iter().map(|(name, value)| (name, OsString::from(value))));
+        }
+
+        if let Some(media_type) = &media_type {
+            env.push(("SHELL_SERVE_MEDIA_TYPE", OsString::from(media_type)));
+        }
+
+        // spooled request body, deleted once the handler exits, which may be
+        // after the response started
+        let mut body_file = None;
+        let mut multipart_body = None;
+
+        let body_file_name = String::from("body_file");
+
+        let stdin_body = match route.options().body {
+            BodyMode::Stdin => Some(body),
+            BodyMode::File => {
+                let stream_reader = body.into_reader(max_body_size);
+                let mut stream_reader = std::pin::pin!(stream_reader);
+
+                let temp_dir = self.config.temp_dir.as_deref();
+                let file = spool_to_file(&mut stream_reader, temp_dir)
+                    .await
+                    .map_err(|e| body_error(e.into()))?;
+
+                params.push((&body_file_name, file.path().to_string_lossy().to_string()));
+                env.push(("SHELL_SERVE_BODY_FILE", file.path().as_os_str().to_owned()));
+
+                body_file = Some(file);
+                None
+            },
+            BodyMode::Multipart => {
+                let content_type = route_req.headers.get("content-type")
+                    .map(String::as_str)
+                    .unwrap_or_default();
+                let boundary = multer::parse_boundary(content_type)
+                    .map_err(|_| RouterError::UnsupportedMediaType(content_type.to_string()))?;
+
+                let stream = body.into_stream(max_body_size);
+                let temp_dir = self.config.temp_dir.as_deref();
+                let parts = multipart_body.insert(
+                    spool_multipart(stream, boundary, route.options(), max_body_size, temp_dir).await?
+                );
+
+                params.extend(parts.captures.iter().map(|(k, v)| (k, v.clone())));
+                env.push(("SHELL_SERVE_MULTIPART_MANIFEST", parts.manifest_path.as_os_str().to_owned()));
+
+                None
+            }
+        };
+
+        let mut cmd = route.get_command(params)?;
+        cmd.envs(env);
+
+        let mut proc = RouteProcess::spawn(cmd)?;
+        proc.keep_until_exit((body_file, multipart_body));
+
+        if let Some(body) = stdin_body.filter(|b| !b.is_empty()) {
+            let stream_reader = body.into_reader(max_body_size);
             let mut stream_reader = std::pin::pin!(stream_reader);
 
-            proc.load_stdin(&mut stream_reader)
-                .await?
-        } else {
-            &mut proc
+            if let Err(e) = proc.load_stdin(&mut stream_reader).await {
+                if let Err(kill_err) = proc.kill().await {
+                    println!("[{}] failed to kill handler: {}", info.request_id, error_chain(&kill_err));
+                }
+
+                return Err(body_error(e));
+            }
+        }
+
+        let wait = proc.wait(route.options().stream);
+        let mut result = match self.config.handler_timeout {
+            Some(timeout) => {
+                let result = tokio::time::timeout(timeout, wait).await;
+                match result {
+                    Ok(result) => result?,
+                    Err(_) => {
+                        if let Err(e) = proc.kill().await {
+                            println!("[{}] failed to kill handler: {}", info.request_id, error_chain(&e));
+                        }
+                        return Err(Error::RouteTimeout(timeout).into());
+                    }
+                }
+            },
+            None => wait.await?
         };
 
-        let result = proc.wait()
-            .await?;
+        if result.passed && route.options().may_pass {
+            return Ok(None);
+        }
 
-        Ok(route_response(result))
+        let has_content_type = result.headers.iter()
+            .any(|(k, _)| k.eq_ignore_ascii_case("content-type"));
+        if let Some(media_type) = media_type.filter(|_| !has_content_type) {
+            result.headers.push((header::CONTENT_TYPE.to_string(), media_type));
+        }
+
+        if !vary.is_empty() {
+            result.headers.push((header::VARY.to_string(), vary.join(", ")));
+        }
+
+        let status = result.status;
+
+        // never sent to the client, which mustn't learn server paths.
+        // X-Accel-Redirect is taken as a path too, X-Sendfile wins over it.
+        let (mut sendfile, mut accel_redirect) = (None, None);
+        result.headers.retain(|(k, v)| {
+            if k.eq_ignore_ascii_case("x-sendfile") {
+                sendfile = Some(v.clone());
+            } else if k.eq_ignore_ascii_case("x-accel-redirect") {
+                accel_redirect = Some(v.clone());
+            }
+            !["x-sendfile", "x-accel-redirect", "pass"].iter().any(|name| k.eq_ignore_ascii_case(name))
+        });
+        let sendfile = sendfile.or(accel_redirect);
+
+        if let Some(allowed) = &route.options().sendfile {
+            if let Some(path) = sendfile.filter(|_| status.is_success()) {
+                let mut response = send_file(&path, allowed, &parts.headers).await?;
+                add_handler_headers(&mut response, result.headers)?;
+                return Ok(Some(response));
+            }
+        }
+        if (status.is_client_error() || status.is_server_error())
+            && self.config.error_pages.find(status).is_some()
+        {
+            // peek at stdout to fall back to error page when handler didn't write a body
+            let mut head = vec![0; 8 * 1024];
+            let len = result.stdout.read(&mut head)
+                .await
+                .map_err(Error::from)?;
+
+            if len == 0 {
+                let reason = format!("Handler responded with status {status}");
+                let page = self.error_page_response(info, status, reason, result.headers.clone())
+                    .await;
+                if let Some(response) = page {
+                    return Ok(Some(response));
+                }
+            }
+
+            head.truncate(len);
+            let body = Cursor::new(head).chain(result.stdout);
+            return Ok(Some(route_response(status, result.headers, body)?));
+        }
+
+        Ok(Some(route_response(status, result.headers, result.stdout)?))
+    }
+}
+
+/// Add headers written by handler to file response, a handler `Content-Type`
+/// replaces the guessed one
+fn add_handler_headers(response: &mut ServiceResponse, headers: Vec<(String, String)>) -> Result<(), Error> {
+    for (name, value) in headers {
+        let name = header::HeaderName::from_str(&name)
+            .map_err(|e| Error::InvalidHeader(e.to_string()))?;
+        let value = header::HeaderValue::from_str(&value)
+            .map_err(|e| Error::InvalidHeader(e.to_string()))?;
+
+        match name.as_str() {
+            "status" | "content-length" => (),
+            "content-type" => {
+                response.headers_mut().insert(name, value);
+            },
+            _ => {
+                response.headers_mut().append(name, value);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Request body errors mapped to `PayloadTooLarge` when caused by the limit
+fn body_error(err: Error) -> RouterError {
+    match err {
+        Error::RouteIoError(e) => match body_too_large(&e) {
+            Some(limit) => RouterError::PayloadTooLarge(limit),
+            None => Error::RouteIoError(e).into()
+        },
+        e => e.into()
     }
 }
 
-fn empty_response(status: StatusCode) -> ServiceResponse {
-    let empty_body = Empty::<body::Bytes>::new()
-        .map_err(|never| match never {});
+/// Request details kept for logging and error pages after the request is consumed
+struct RequestInfo {
+    request_id: String,
+    method: String,
+    path: String
+}
+
+impl RequestInfo {
+    fn new<B>(req: &Request<B>) -> Self {
+        RequestInfo {
+            request_id: request_id(req),
+            method: req.method().to_string(),
+            path: req.uri().path().to_owned()
+        }
+    }
+}
+
+async fn render_error_page(page: &ErrorPage, ctx: &ErrorContext<'_>) -> Result<(Vec<(String, String)>, ResponseBody), Error> {
+    match page {
+        ErrorPage::File { path, content_type } => {
+            let content = tokio::fs::read(path).await?;
+            let content_type = content_type.clone()
+                .unwrap_or_else(|| file_content_type(path).to_string());
+
+            Ok((vec![(header::CONTENT_TYPE.to_string(), content_type)], full_body(content)))
+        },
+        ErrorPage::Handler(handler) => {
+            let mut proc = RouteProcess::spawn(ctx.command(handler)?)?;
+            let result = proc.wait(false).await?;
 
-    Response::builder()
-        .status(status)
-        .body(empty_body.boxed())
-        .unwrap()
+            Ok((result.headers, stream_body(result.stdout)))
+        }
+    }
 }
 
-fn body_stream_reader(body: body::Incoming) -> impl AsyncRead {
-    let stream_of_frames = BodyStream::new(body);
-    let stream_of_bytes = stream_of_frames
-        .try_filter_map(|frame| async move { Ok(frame.into_data().ok()) })
-        .map_err(|err| IoError::new(std::io::ErrorKind::Other, err));
-    StreamReader::new(stream_of_bytes)
+/// Use the request id given by an upstream proxy, or generate a new one
+fn request_id<B>(req: &Request<B>) -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let upstream_id = req.headers().get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .filter(|v| v.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'));
+
+    if let Some(id) = upstream_id {
+        id.to_string()
+    } else {
+        let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+        format!("{:x}-{:x}", std::process::id(), count)
+    }
 }
 
-fn to_route_req(req: &Request<body::Incoming>) -> Result<RouteRequest, RouterError> {
-    let method = req.method().as_str();
-    let method = Method::from_str(method)
-        .map_err(|_| RouterError::UnsupportedMethod(method.into()))?;
+fn to_route_req(req: &request::Parts) -> Result<RouteRequest, RouterError> {
+    // HEAD matches GET routes, only static routes answer it
+    let method = match req.method.as_str() {
+        "HEAD" => Method::Get,
+        method => Method::from_str(method)
+            .map_err(|_| RouterError::UnsupportedMethod(method.into()))?
+    };
+
+    // absolute-form or HTTP/2 authority, otherwise Host header
+    let host = req.uri.authority()
+        .map(|a| a.host().to_string())
+        .or_else(|| {
+            let host = req.headers.get(header::HOST)?.to_str().ok()?;
+            Some(strip_port(host).to_string())
+        })
+        .map(|h| h.to_ascii_lowercase());
 
-    let path = PathBuf::from(req.uri().path());
+    if let Some(host) = &host {
+        let name = host.strip_suffix('.').unwrap_or(host);
+        let valid = name.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.[]:".contains(&b))
+            && (name.starts_with('[') || name.split('.').all(|label| !label.is_empty()));
 
-    let query = if let Some(query) = req.uri().query() {
+        if !valid {
+            return Err(RouterError::InvalidRequest(format!("invalid host '{host}'")));
+        }
+    }
+
+    let path = PathBuf::from(req.uri.path());
+
+    let query = if let Some(query) = req.uri.query() {
         urlparse::parse_qs(query).into_iter()
             .map(|(key, val)| (key, val.join(",")))
             .collect()
@@ -82,26 +616,519 @@ fn to_route_req(req: &Request<body::Incoming>) -> Result<RouteRequest, RouterErr
         HashMap::new()
     };
 
-    let headers = req.headers().into_iter()
-        .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
-        .collect();
+    let headers = req.headers.iter()
+        .map(|(k, v)| match v.to_str() {
+            Ok(v) => Ok((k.to_string(), v.to_string())),
+            Err(_) => Err(RouterError::InvalidRequest(format!("non-ascii value in header '{k}'")))
+        })
+        .collect::<Result<_, _>>()?;
 
-    Ok(RouteRequest { method, path, query, headers })
+    let connection = req.extensions.get::<ConnectionInfo>()
+        .cloned()
+        .unwrap_or_default();
+
+    Ok(RouteRequest { method, host, path, query, headers, body: None, connection })
 }
 
-fn route_response(res: RouteResponse) -> Response<BoxBody<body::Bytes, IoError>> {
-    let reader_stream = ReaderStream::new(res.stdout);
-    let stream_body = StreamBody::new(reader_stream.map_ok(body::Frame::data));
-    let boxed_body = stream_body.boxed();
+pub(crate) fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        // IPv6 literal, e.g. "[::1]:8000"
+        host.find(']').map_or(host, |end| &host[..=end])
+    } else {
+        host.split_once(':').map_or(host, |(host, _)| host)
+    }
+}
+
+pub(crate) fn full_body<B: Into<body::Bytes>>(content: B) -> ResponseBody {
+    Full::new(content.into())
+        .map_err(|never| match never {})
+        .boxed()
+}
 
+pub(crate) fn stream_body<R>(reader: R) -> ResponseBody
+    where R: AsyncRead + Send + Sync + 'static
+{
+    let reader_stream = ReaderStream::new(reader);
+    StreamBody::new(reader_stream.map_ok(body::Frame::data))
+        .boxed()
+}
+
+fn route_response<R>(status: StatusCode, headers: Vec<(String, String)>, stdout: R) -> Result<ServiceResponse, Error>
+    where R: AsyncRead + Send + Sync + 'static
+{
     let mut builder = Response::builder();
 
-    for (name, value) in res.headers {
+    for (name, value) in headers {
         builder = builder.header(name, value);
     }
 
-    builder.status(res.status)
-        //.body(StreamBody::new(self.stdout))
-        .body(boxed_body)
-        .unwrap()
+    builder.status(status)
+        .body(stream_body(stdout))
+        .map_err(|e| Error::InvalidHeader(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        builtin::{FixedResponse, Redirect}, error_page::ErrorPages, route::{Route, RouteOptions}, router::RouterConfig,
+        static_files::StaticDir, tls::TlsInfo
+    };
+    use hyper::HeaderMap;
+    use std::{os::unix::fs::PermissionsExt, path::Path};
+
+    /// Write executable shell script `name` into `dir`
+    fn script(dir: &Path, name: &str, body: &str) -> String {
+        let path = dir.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    fn route(route: &str, options: RouteOptions) -> Route {
+        route.parse::<Route>().unwrap().with_options(options)
+    }
+
+    async fn send(router: &ShellRouter, req: Request<Full<body::Bytes>>) -> (StatusCode, HeaderMap, String) {
+        let (parts, body) = router.call(req).await.unwrap().into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (parts.status, parts.headers, String::from_utf8_lossy(&body).to_string())
+    }
+
+    fn request(method: &str, uri: &str, body: &str) -> Request<Full<body::Bytes>> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Full::new(body::Bytes::from(body.to_string())))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_body_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = script(dir.path(), "up.sh", "echo \"$1\"; cat \"$SHELL_SERVE_BODY_FILE\"");
+        let options = RouteOptions { body: BodyMode::File, ..Default::default() };
+        let config = RouterConfig { temp_dir: Some(dir.path().join("tmp")), ..Default::default() };
+        std::fs::create_dir(dir.path().join("tmp")).unwrap();
+
+        let router = ShellRouter::new(vec![route(&format!("POST:/up {handler} ${{body_file}}"), options)], config);
+        let (status, _, body) = send(&router, request("POST", "/up", "uploaded data")).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let (path, content) = body.split_once('\n').unwrap();
+        assert!(path.starts_with(&dir.path().join("tmp").to_string_lossy().to_string()));
+        assert_eq!(content, "uploaded data");
+
+        // deleted once the handler is done
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!Path::new(path).exists());
+    }
+
+    #[tokio::test]
+    async fn test_stdin_closed_without_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let error_page = script(dir.path(), "error.sh", "cat; echo not found");
+        let pages = ErrorPages::new(vec![("404".parse().unwrap(), ErrorPage::Handler(error_page))]);
+        let config = RouterConfig { error_pages: pages, ..Default::default() };
+        let routes = vec![route("GET:/cat cat", RouteOptions::default()), route("POST:/cat cat", RouteOptions::default())];
+        let router = ShellRouter::new(routes, config);
+
+        // handlers reading stdin get an empty body, instead of waiting for one
+        let timeout = std::time::Duration::from_secs(5);
+        let (status, _, body) = tokio::time::timeout(timeout, send(&router, request("GET", "/cat", ""))).await
+            .expect("handler should see the end of stdin");
+        assert_eq!((status, body.as_str()), (StatusCode::OK, ""));
+
+        let (status, _, body) = tokio::time::timeout(timeout, send(&router, request("POST", "/cat", ""))).await
+            .expect("handler should see the end of stdin");
+        assert_eq!((status, body.as_str()), (StatusCode::OK, ""));
+
+        let (status, _, body) = tokio::time::timeout(timeout, send(&router, request("GET", "/missing", ""))).await
+            .expect("error page handler should see the end of stdin");
+        assert_eq!((status, body.as_str()), (StatusCode::NOT_FOUND, "not found\n"));
+    }
+
+    #[tokio::test]
+    async fn test_error_pages() {
+        let dir = tempfile::tempdir().unwrap();
+        let error_page = script(
+            dir.path(),
+            "error.sh",
+            "echo \"$SHELL_SERVE_ERROR_STATUS $SHELL_SERVE_REQUEST_METHOD $SHELL_SERVE_REQUEST_PATH: $SHELL_SERVE_ERROR_REASON\""
+        );
+        let timeout_page = script(dir.path(), "timeout.sh", "echo timed out");
+        let pages = ErrorPages::new(vec![
+            ("4xx".parse().unwrap(), ErrorPage::Handler(error_page)),
+            ("timeout".parse().unwrap(), ErrorPage::Handler(timeout_page))
+        ]);
+        let config = RouterConfig {
+            error_pages: pages,
+            handler_timeout: Some(std::time::Duration::from_millis(200)),
+            ..Default::default()
+        };
+
+        let gone = script(dir.path(), "gone.sh", "echo 'Status: 410' >$SHELL_SERVE_PIPE");
+        let teapot = script(dir.path(), "teapot.sh", "echo 'Status: 418' >$SHELL_SERVE_PIPE; echo short and stout");
+        let routes = vec![
+            route(&format!("DELETE:/gone {gone}"), RouteOptions::default()),
+            route(&format!("GET:/teapot {teapot}"), RouteOptions::default()),
+            route("GET:/slow sleep 5", RouteOptions::default())
+        ];
+        let router = ShellRouter::new(routes, config);
+
+        // an error status without a body falls back to the error page, which
+        // gets the request and the reason
+        let (status, _, body) = send(&router, request("DELETE", "/gone", "")).await;
+        assert_eq!(status, StatusCode::GONE);
+        assert_eq!(body, "410 DELETE /gone: Handler responded with status 410 Gone\n");
+
+        // the handler's own body is kept
+        let (status, _, body) = send(&router, request("GET", "/teapot", "")).await;
+        assert_eq!(status, StatusCode::IM_A_TEAPOT);
+        assert_eq!(body, "short and stout\n");
+
+        let (status, _, body) = send(&router, request("GET", "/missing", "")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.starts_with("404 GET /missing: "), "{body}");
+
+        // handlers past the timeout are killed
+        let start = std::time::Instant::now();
+        let (status, _, body) = send(&router, request("GET", "/slow", "")).await;
+        assert!(start.elapsed() < std::time::Duration::from_secs(1));
+        assert_eq!((status, body.as_str()), (StatusCode::GATEWAY_TIMEOUT, "timed out\n"));
+    }
+
+    #[tokio::test]
+    async fn test_problem_response() {
+        let routes = vec![route("GET:/broken /nonexistent/handler", RouteOptions::default())];
+        let router = ShellRouter::new(routes, RouterConfig::default());
+
+        let (status, headers, body) = send(&router, request("GET", "/broken", "")).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(headers[header::CONTENT_TYPE], problem::CONTENT_TYPE);
+        let problem: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(problem["status"], 500);
+        assert_eq!(problem["request_id"], headers[REQUEST_ID_HEADER].to_str().unwrap());
+        // internal details only with `debug`
+        assert!(problem.get("detail").is_none());
+
+        // an upstream request id is kept, unless it isn't a plain token
+        let mut req = request("GET", "/broken", "");
+        req.headers_mut().insert(REQUEST_ID_HEADER, "upstream-42".parse().unwrap());
+        let (_, headers, body) = send(&router, req).await;
+        assert_eq!(headers[REQUEST_ID_HEADER], "upstream-42");
+        let problem: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(problem["request_id"], "upstream-42");
+
+        let mut req = request("GET", "/broken", "");
+        req.headers_mut().insert(REQUEST_ID_HEADER, "bad id; x".parse().unwrap());
+        let (_, headers, _) = send(&router, req).await;
+        assert_ne!(headers[REQUEST_ID_HEADER], "bad id; x");
+    }
+
+    #[tokio::test]
+    async fn test_body_file_too_large() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = script(dir.path(), "up.sh", "cat \"$SHELL_SERVE_BODY_FILE\"");
+        let options = RouteOptions { body: BodyMode::File, max_body_size: Some(4), ..Default::default() };
+        let config = RouterConfig { temp_dir: Some(dir.path().join("tmp")), ..Default::default() };
+        std::fs::create_dir(dir.path().join("tmp")).unwrap();
+
+        let router = ShellRouter::new(vec![route(&format!("POST:/up {handler}"), options)], config);
+        let (status, _, _) = send(&router, request("POST", "/up", "too large")).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(std::fs::read_dir(dir.path().join("tmp")).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_host() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = script(dir.path(), "sub.sh", "echo \"$1\"");
+
+        let router = ShellRouter::new(vec![route(&format!("GET://{{sub..}}.example.test/ {handler} ${{sub}}"), RouteOptions::default())], RouterConfig::default());
+        let (status, _, body) = send(&router, request("GET", "http://a.b.example.test/", "")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "a.b\n"));
+
+        for host in ["...example.test", "a..example.test", "a/b.example.test"] {
+            let req = Request::builder().uri("/").header(header::HOST, host).body(Full::default()).unwrap();
+            let (status, _, _) = send(&router, req).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{host}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_host() {
+        let routes = || vec![
+            route("GET:/{path..} echo api ${path}", RouteOptions::default()).with_host("api.local").unwrap(),
+            route("GET:/ echo admin", RouteOptions::default()).with_host("admin.local").unwrap()
+        ];
+        let get = |host: &str, uri: &str| {
+            let mut req = request("GET", uri, "");
+            req.headers_mut().insert(header::HOST, host.parse().unwrap());
+            req
+        };
+
+        let config = RouterConfig { default_host: Some("api.local".to_string()), ..Default::default() };
+        let router = ShellRouter::new(routes(), config);
+
+        // unknown hosts are routed as the default host
+        let (status, _, body) = send(&router, get("unknown.test", "/status")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "api status\n"));
+
+        let (status, _, body) = send(&router, get("admin.local:8080", "/")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "admin\n"));
+
+        let router = ShellRouter::new(routes(), RouterConfig::default());
+        let (status, _, _) = send(&router, get("unknown.test", "/status")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_negotiation_errors_vary() {
+        let json = RouteOptions { accept: Some(vec!["application/json".into()]), ..Default::default() };
+        let csv = RouteOptions { content_type: Some(vec!["text/csv".into()]), ..Default::default() };
+        let routes = vec![route("GET:/report echo", json), route("POST:/import cat", csv)];
+        let router = ShellRouter::new(routes, RouterConfig::default());
+
+        let mut req = request("GET", "/report", "");
+        req.headers_mut().insert(header::ACCEPT, "text/html".parse().unwrap());
+        let (status, headers, _) = send(&router, req).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(headers[header::VARY], "Accept");
+
+        let mut req = request("POST", "/import", "{}");
+        req.headers_mut().insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        let (status, headers, _) = send(&router, req).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(headers[header::VARY], "Content-Type");
+    }
+
+    #[tokio::test]
+    async fn test_static_methods() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a.txt"), "static").unwrap();
+        let handler = script(root.path(), "h.sh", "echo \"$1\"");
+
+        let dir = StaticDir::new(root.path().to_string_lossy().to_string());
+        let get_route = Route::new("GET:/files/{path..}", RouteAction::Static(dir.clone())).unwrap();
+        let put_route = Route::new("PUT:/files/{path..}", RouteAction::Static(dir)).unwrap();
+        let routes = vec![get_route, put_route, route(&format!("GET:/h/{{path..}} {handler} ${{path}}"), RouteOptions::default())];
+        let router = ShellRouter::new(routes, RouterConfig::default());
+
+        let (status, headers, body) = send(&router, request("HEAD", "/files/a.txt", "")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, ""));
+        assert_eq!(headers[header::CONTENT_LENGTH], "6");
+
+        let (status, headers, _) = send(&router, request("PUT", "/files/a.txt", "")).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(headers[header::ALLOW], "GET, HEAD");
+
+        let (status, _, _) = send(&router, request("GET", "/files/../a.txt", "")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = send(&router, request("HEAD", "/h/a", "")).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+
+        let (status, _, _) = send(&router, request("GET", "/h/a/../b", "")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_builtin_routes() {
+        let teapot = FixedResponse {
+            status: StatusCode::IM_A_TEAPOT,
+            headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+            body: "I'm a teapot".to_string()
+        };
+        let https = Redirect { https: true, ..Redirect::new(None) };
+        let routes = vec![
+            Route::new("GET:/teapot", RouteAction::Respond(teapot)).unwrap(),
+            Route::new("GET:/legacy/{path..}", RouteAction::Rewrite("/new/${path}".to_string())).unwrap(),
+            route("GET:/new/{path..} echo ${path}", RouteOptions::default()),
+            Route::new("GET:/loop/{n}", RouteAction::Rewrite("/loop/${n}x".to_string())).unwrap(),
+            Route::new("GET:/secure", RouteAction::Redirect(https)).unwrap(),
+            route("GET:/secure echo secure", RouteOptions::default())
+        ];
+        let router = ShellRouter::new(routes, RouterConfig { debug: true, ..Default::default() });
+
+        let (status, headers, body) = send(&router, request("GET", "/teapot", "")).await;
+        assert_eq!(status, StatusCode::IM_A_TEAPOT);
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(body, "I'm a teapot");
+
+        // rewritten paths match routes again
+        let (status, _, body) = send(&router, request("GET", "/legacy/a/b", "")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "a/b\n"));
+
+        let (status, _, body) = send(&router, request("GET", "/loop/x", "")).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.contains("more than 10 rewrites"), "{body}");
+
+        // over TLS, the HTTPS redirect doesn't apply and the next route does
+        let mut req = request("GET", "/secure", "");
+        req.headers_mut().insert(header::HOST, "example.test:8080".parse().unwrap());
+        let (status, headers, _) = send(&router, req).await;
+        assert_eq!(status, StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(headers[header::LOCATION], "https://example.test/secure");
+
+        let mut req = request("GET", "/secure", "");
+        req.headers_mut().insert(header::HOST, "example.test".parse().unwrap());
+        let tls = TlsInfo { protocol: "TLSv1.3".to_string(), cipher: None, server_name: None, alpn: None, client_cert: None };
+        req.extensions_mut().insert(ConnectionInfo { tls: Some(tls), ..Default::default() });
+        let (status, _, body) = send(&router, req).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "secure\n"));
+    }
+
+    #[tokio::test]
+    async fn test_trailing_slash() {
+        let strict = RouteOptions { trailing_slash: Some(TrailingSlash::Strict), ..Default::default() };
+        let routes = vec![
+            route("GET:/docs/ echo docs", RouteOptions::default()),
+            route("GET:/api/{id} echo ${id}", strict)
+        ];
+        let config = RouterConfig { trailing_slash: TrailingSlash::Redirect, ..Default::default() };
+        let router = ShellRouter::new(routes, config);
+
+        // redirected to the canonical path, keeping the query
+        let (status, headers, _) = send(&router, request("GET", "//docs?page=2", "")).await;
+        assert_eq!(status, StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(headers[header::LOCATION], "/docs/?page=2");
+
+        let (status, _, body) = send(&router, request("GET", "/docs/", "")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "docs\n"));
+
+        // the route's own policy wins, only the canonical path matches
+        let (status, _, _) = send(&router, request("GET", "/api/1/", "")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, _) = send(&router, request("GET", "/api//1", "")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _, body) = send(&router, request("GET", "/api/1", "")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_sendfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = dir.path().join("files");
+        std::fs::create_dir(&files).unwrap();
+        std::fs::write(files.join("a.txt"), "file content").unwrap();
+        std::fs::write(dir.path().join("secret.txt"), "secret").unwrap();
+
+        let handler = script(dir.path(), "send.sh", &format!(
+            "printf 'X-Sendfile: {}/%s\\nX-Accel-Redirect: /internal\\nContent-Disposition: attachment\\n' \"$1\" >\"$SHELL_SERVE_PIPE\"",
+            dir.path().display()
+        ));
+        let options = RouteOptions { sendfile: Some(vec![files.clone()]), ..Default::default() };
+        let routes = vec![
+            route(&format!("GET:/send/{{path..}} {handler} ${{path}}"), options),
+            route(&format!("GET:/plain/{{path..}} {handler} ${{path}}"), RouteOptions::default())
+        ];
+        let router = ShellRouter::new(routes, RouterConfig::default());
+
+        let (status, headers, body) = send(&router, request("GET", "/send/files/a.txt", "")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "file content"));
+        assert_eq!(headers[header::CONTENT_DISPOSITION], "attachment");
+        assert!(!headers.contains_key("x-sendfile") && !headers.contains_key("x-accel-redirect"));
+
+        // X-Accel-Redirect names a file the same way, in the same dirs
+        let accel = script(dir.path(), "accel.sh", "echo \"X-Accel-Redirect: $1\" >\"$SHELL_SERVE_PIPE\"");
+        let options = RouteOptions { sendfile: Some(vec![files.clone()]), ..Default::default() };
+        let accel_route = route(&format!("GET:/{{path..}} {accel} /${{path}}"), options);
+        let accel_router = ShellRouter::new(vec![accel_route], RouterConfig::default());
+        let path = files.join("a.txt");
+        let (status, headers, body) = send(&accel_router, request("GET", &path.to_string_lossy(), "")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "file content"));
+        assert!(!headers.contains_key("x-accel-redirect"));
+        let secret = dir.path().join("secret.txt");
+        let (status, headers, _) = send(&accel_router, request("GET", &secret.to_string_lossy(), "")).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert!(!headers.contains_key("x-accel-redirect"));
+
+        // outside of the allowed dirs, including through symlinks
+        std::os::unix::fs::symlink(dir.path().join("secret.txt"), files.join("link.txt")).unwrap();
+        let (status, _, _) = send(&router, request("GET", "/send/secret.txt", "")).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        let (status, _, _) = send(&router, request("GET", "/send/files/link.txt", "")).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+        // routes without `sendfile` strip the headers
+        let (status, headers, body) = send(&router, request("GET", "/plain/files/a.txt", "")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, ""));
+        assert!(!headers.contains_key("x-sendfile") && !headers.contains_key("x-accel-redirect"));
+        assert_eq!(headers[header::CONTENT_DISPOSITION], "attachment");
+    }
+
+    #[tokio::test]
+    async fn test_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        let exit = script(dir.path(), "exit.sh", "echo discarded; exit 100");
+        let header = script(dir.path(), "header.sh", "echo Pass: 1 >\"$SHELL_SERVE_PIPE\"; echo discarded");
+        let last = script(dir.path(), "last.sh", "echo \"$1\"; cat");
+        let may_pass = || RouteOptions { may_pass: true, ..Default::default() };
+
+        let routes = vec![
+            route(&format!("POST:/exit {exit}"), may_pass()),
+            route(&format!("POST:/{{name}} {header}"), may_pass()),
+            route(&format!("POST:/{{name}} {last} ${{name}}"), RouteOptions::default())
+        ];
+        let router = ShellRouter::new(routes, RouterConfig::default());
+
+        // both handlers pass, and the body is replayed to the last one
+        let (status, headers, body) = send(&router, request("POST", "/exit", "body")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "exit\nbody"));
+        assert!(!headers.contains_key("pass"));
+
+        let (status, _, body) = send(&router, request("POST", "/other", "body")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "other\nbody"));
+    }
+
+    #[tokio::test]
+    async fn test_replace_in_flight() {
+        let dir = tempfile::tempdir().unwrap();
+        let slow = script(dir.path(), "slow.sh", "sleep 0.3; exit 100");
+        let old = script(dir.path(), "old.sh", "echo old");
+        let new = script(dir.path(), "new.sh", "echo new");
+        let may_pass = RouteOptions { may_pass: true, ..Default::default() };
+
+        let routes = vec![
+            route(&format!("GET:/slow {slow}"), may_pass),
+            route(&format!("GET:/{{name}} {old}"), RouteOptions::default())
+        ];
+        let router = ShellRouter::new(routes, RouterConfig::default());
+        let in_flight = tokio::spawn({
+            let router = router.clone();
+            async move { send(&router, request("GET", "/slow", "")).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        // the request passed on after the swap still finds its own table's routes
+        let routes = vec![route(&format!("GET:/{{name}} {new}"), RouteOptions::default())];
+        router.replace(routes, RouterConfig::default());
+        let (status, _, body) = in_flight.await.unwrap();
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "old\n"));
+
+        let (_, _, body) = send(&router, request("GET", "/slow", "")).await;
+        assert_eq!(body, "new\n");
+    }
+
+    #[tokio::test]
+    async fn test_pass_not_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let exit = script(dir.path(), "exit.sh", "echo exited; exit 100");
+        let header = script(dir.path(), "header.sh", "echo Pass: 1 >\"$SHELL_SERVE_PIPE\"; echo responded");
+        let last = script(dir.path(), "last.sh", "echo last");
+
+        let routes = vec![
+            route(&format!("GET:/exit {exit}"), RouteOptions::default()),
+            route(&format!("GET:/header {header}"), RouteOptions::default()),
+            route(&format!("GET:/{{name}} {last}"), RouteOptions::default())
+        ];
+        let router = ShellRouter::new(routes, RouterConfig::default());
+
+        let (status, _, body) = send(&router, request("GET", "/exit", "")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::INTERNAL_SERVER_ERROR, "exited\n"));
+
+        let (status, headers, body) = send(&router, request("GET", "/header", "")).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "responded\n"));
+        assert!(!headers.contains_key("pass"));
+    }
 }
@@ -0,0 +1,1071 @@
+use crate::listener::{ListenAddr, Listener, ListenerConfig};
+use hyper::{
+    body::{self, Body, Frame, SizeHint},
+    header::{self, HeaderValue},
+    service::service_fn,
+    Request
+};
+use hyper_util::{rt::{TokioExecutor, TokioIo}, server::conn::auto};
+use shell_serve::{
+    connection::ConnectionInfo,
+    http3,
+    proxy::read_proxy_header,
+    router::ShellRouter,
+    tls::{CertResolver, TlsInfo}
+};
+use std::{
+    convert::Infallible, io, net::SocketAddr, os::fd::OwnedFd, pin::Pin,
+    str::FromStr, sync::Arc, task::{Context, Poll}, time::Duration
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{watch, OwnedSemaphorePermit, Semaphore}
+};
+use tokio_rustls::TlsAcceptor;
+
+
+/// HTTP versions served on a connection
+#[derive(Clone, Copy)]
+enum Protocols {
+    Http1,
+    Http2,
+    /// Either, detected from the HTTP/2 connection preface
+    Auto
+}
+
+/// What happens to connections accepted while at `max_connections`
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum OverLimit {
+    /// Leave them waiting in the listen backlog until a connection closes
+    #[default]
+    Queue,
+    /// Close them right away
+    Reject
+}
+
+impl FromStr for OverLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "queue" => Ok(OverLimit::Queue),
+            "reject" => Ok(OverLimit::Reject),
+            _ => Err(format!("invalid over limit policy '{s}', expected queue or reject"))
+        }
+    }
+}
+
+/// Limits on connections, against clients holding on to them
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    /// Time for a client to finish the TLS handshake, and to send the
+    /// headers of an HTTP/1 request
+    pub header_timeout: Duration,
+    /// Time a connection is kept open without requests
+    pub keepalive_timeout: Duration,
+    /// Maximum size of request headers, hyper's default when unset
+    pub max_header_size: Option<usize>,
+    /// Maximum open TCP and Unix socket connections, across all listeners
+    pub max_connections: Option<usize>,
+    pub over_limit: OverLimit
+}
+
+/// Count of open connections, shared by all servers, and the signal for them
+/// to shut down
+#[derive(Clone)]
+pub struct Connections {
+    count: Arc<watch::Sender<usize>>,
+    shutdown: Arc<watch::Sender<bool>>,
+    limits: Arc<ConnectionLimits>,
+    /// A permit for each connection allowed by `max_connections`
+    slots: Option<Arc<Semaphore>>
+}
+
+impl Connections {
+    pub fn new(limits: ConnectionLimits) -> Self {
+        Connections {
+            count: Arc::new(watch::Sender::new(0)),
+            shutdown: Arc::new(watch::Sender::new(false)),
+            slots: limits.max_connections.map(|max| Arc::new(Semaphore::new(max))),
+            limits: Arc::new(limits)
+        }
+    }
+
+    /// Wait until there's room for another connection, when over limit
+    /// connections are queued
+    async fn queue_slot(&self) -> Option<OwnedSemaphorePermit> {
+        let slots = self.slots.as_ref().filter(|_| self.limits.over_limit == OverLimit::Queue)?;
+
+        if slots.available_permits() == 0 {
+            println!(
+                "Connection limit of {} reached, queueing new connections",
+                self.limits.max_connections.unwrap_or_default()
+            );
+        }
+        // can't fail, the semaphore is never closed
+        slots.clone().acquire_owned().await.ok()
+    }
+
+    /// Count accepted connection as open, taking a slot unless it already
+    /// has one from `queue_slot`. `None` when there's no slot left for it.
+    fn open(&self, slot: Option<OwnedSemaphorePermit>) -> Option<OpenConnection> {
+        let slot = match (&self.slots, slot) {
+            (Some(slots), None) => Some(slots.clone().try_acquire_owned().ok()?),
+            (_, slot) => slot
+        };
+
+        self.count.send_modify(|count| *count += 1);
+        Some(OpenConnection { connections: self.clone(), _slot: slot })
+    }
+
+    /// Count QUIC connection as open, these aren't limited by `max_connections`
+    fn open_quic(&self) -> OpenConnection {
+        self.count.send_modify(|count| *count += 1);
+        OpenConnection { connections: self.clone(), _slot: None }
+    }
+
+    /// Stop accepting connections, and close open ones once their current
+    /// requests are done
+    pub fn shut_down(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    async fn shutting_down(&self) {
+        // can't fail, the sender is kept in `self`
+        let _ = self.shutdown.subscribe().wait_for(|shutdown| *shutdown).await;
+    }
+
+    /// Wait until all connections are closed
+    pub async fn closed(&self) {
+        let _ = self.count.subscribe().wait_for(|count| *count == 0).await;
+    }
+
+    /// Resolves once there have been no open connections for `timeout`
+    pub async fn idle(&self, timeout: Duration) {
+        let mut count = self.count.subscribe();
+
+        loop {
+            if *count.borrow_and_update() == 0 {
+                tokio::select! {
+                    _ = tokio::time::sleep(timeout) => return,
+                    _ = count.changed() => {}
+                }
+            } else {
+                // can't fail, the sender is kept in `self`
+                let _ = count.changed().await;
+            }
+        }
+    }
+}
+
+/// Counted as open, and holding its slot, until dropped
+struct OpenConnection {
+    connections: Connections,
+    _slot: Option<OwnedSemaphorePermit>
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.connections.count.send_modify(|count| *count -= 1);
+    }
+}
+
+/// Bound listener serving the routes of one router
+pub struct Server {
+    listener: Listener,
+    router: ShellRouter,
+    acceptor: Option<TlsAcceptor>,
+    endpoint: Option<quinn::Endpoint>,
+    /// Protocols of connections without TLS
+    cleartext: Protocols,
+    /// Connections start with a PROXY protocol header
+    proxy_protocol: bool,
+    /// UDP port HTTP/3 is served on
+    port: u16
+}
+
+impl Server {
+    /// Bind listener, or listen on `socket` passed by the service manager
+    pub async fn bind(
+        config: ListenerConfig,
+        router: ShellRouter,
+        socket: Option<OwnedFd>
+    ) -> anyhow::Result<Self> {
+        let addr = match config.listen {
+            ListenAddr::Ip(ip) => Some(SocketAddr::new(ip, config.port)),
+            ListenAddr::Unix(_) => None
+        };
+
+        let mut endpoint = None;
+        let acceptor = if config.tls_certs.is_empty() {
+            None
+        } else {
+            let resolver = Arc::new(CertResolver::new(config.tls_certs)?);
+            resolver.watch()?;
+
+            let client_ca = config.tls_client_ca;
+            if let (true, Some(addr)) = (config.http3, addr) {
+                let server_config = resolver.server_config(&[http3::ALPN], client_ca.as_ref())?;
+                endpoint = Some(http3::endpoint(addr, server_config)?);
+            }
+
+            let server_config = resolver.server_config(&[b"h2", b"http/1.1"], client_ca.as_ref())?;
+            Some(TlsAcceptor::from(Arc::new(server_config)))
+        };
+
+        if config.http3 && addr.is_none() {
+            anyhow::bail!("HTTP/3 needs an IP address to listen on, not a Unix socket");
+        }
+
+        if config.http3 && endpoint.is_none() {
+            anyhow::bail!("HTTP/3 needs TLS, set 'tls_cert' and 'tls_key'");
+        }
+
+        let listener = match socket {
+            Some(fd) => Listener::from_fd(fd)?,
+            None => Listener::bind(&config.listen, config.port, &config.socket_file).await?
+        };
+        println!("Listening on {}", listener.url(acceptor.is_some()));
+        if endpoint.is_some() {
+            println!("Listening on {} (HTTP/3)", listener.url(true));
+        }
+
+        Ok(Server {
+            listener,
+            router,
+            acceptor,
+            endpoint,
+            cleartext: if config.h2c { Protocols::Auto } else { Protocols::Http1 },
+            proxy_protocol: config.proxy_protocol,
+            port: config.port
+        })
+    }
+
+    /// Accept connections until shutdown, or until accepting fails
+    pub async fn serve(self, connections: Connections) -> io::Result<()> {
+        // HTTP/3 is advertised to clients on TLS connections
+        let alt_svc = self.endpoint.as_ref().map(|endpoint| {
+            let shutdown = connections.shutdown.subscribe();
+            let connections = connections.clone();
+            let open = move || connections.open_quic();
+            tokio::task::spawn(http3::serve(endpoint.clone(), self.router.clone(), open, shutdown));
+            HeaderValue::from_str(&format!("h3=\":{}\"; ma=86400", self.port)).unwrap()
+        });
+
+        loop {
+            // queued connections wait in the listen backlog, not accepted
+            let (stream, conn, open) = tokio::select! {
+                accepted = async {
+                    let slot = connections.queue_slot().await;
+                    let (stream, conn) = self.listener.accept().await?;
+                    io::Result::Ok((stream, conn, connections.open(slot)))
+                } => accepted?,
+                _ = connections.shutting_down() => break
+            };
+            let Some(open) = open else {
+                println!(
+                    "Rejected connection on {}, limit of {} connections reached",
+                    self.listener.url(self.acceptor.is_some()),
+                    connections.limits.max_connections.unwrap_or_default()
+                );
+                continue;
+            };
+            let router = self.router.clone();
+            let acceptor = self.acceptor.clone();
+            let (cleartext, proxy_protocol) = (self.cleartext, self.proxy_protocol);
+            let alt_svc = alt_svc.clone();
+
+            // PROXY header and handshake in the connection task, so a slow
+            // client can't hold up accepting
+            tokio::task::spawn(async move {
+                let (mut stream, mut conn) = (stream, conn);
+                let header_timeout = open.connections.limits.header_timeout;
+
+                if proxy_protocol {
+                    match tokio::time::timeout(header_timeout, read_proxy_header(&mut stream)).await {
+                        Ok(Ok(addr)) => {
+                            // Unix socket peers are trusted as far as the socket's permissions go
+                            let trusted = conn.remote.is_none_or(|remote| {
+                                router.table().config().trusted_proxies.trusts(remote.ip)
+                            });
+                            if let (true, Some(addr)) = (trusted, addr) {
+                                conn.remote = Some(addr.into());
+                            }
+                        },
+                        Ok(Err(err)) => {
+                            println!("Failed to read PROXY protocol header: {err}");
+                            return;
+                        },
+                        Err(_) => {
+                            println!("PROXY protocol header timed out");
+                            return;
+                        }
+                    }
+                }
+
+                let Some(acceptor) = acceptor else {
+                    serve_connection(stream, router, conn, cleartext, None, &open.connections).await;
+                    return;
+                };
+
+                match tokio::time::timeout(header_timeout, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => {
+                        let tls = TlsInfo::new(stream.get_ref().1);
+                        let protocols = match tls.alpn.as_deref() {
+                            Some("h2") => Protocols::Http2,
+                            _ => Protocols::Http1
+                        };
+
+                        let conn = ConnectionInfo { tls: Some(tls), ..conn };
+                        serve_connection(stream, router, conn, protocols, alt_svc, &open.connections).await;
+                    },
+                    Ok(Err(err)) => println!("TLS handshake failed: {err}"),
+                    Err(_) => println!("TLS handshake timed out")
+                }
+            });
+        }
+
+        // open HTTP/3 connections are left to finish, like the others, then
+        // closed before the endpoint goes
+        if let Some(endpoint) = self.endpoint {
+            endpoint.set_server_config(None);
+            connections.closed().await;
+            endpoint.wait_idle().await;
+        }
+
+        Ok(())
+    }
+}
+
+async fn serve_connection<I>(
+    io: I,
+    router: ShellRouter,
+    conn: ConnectionInfo,
+    protocols: Protocols,
+    alt_svc: Option<HeaderValue>,
+    connections: &Connections
+)
+    where I: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let limits = &connections.limits;
+    let phase = Arc::new(watch::Sender::new(Phase::Headers));
+    let timed_out = timed_out(phase.subscribe(), limits);
+    let io = PhaseIo {
+        io,
+        phase: phase.clone(),
+        http1: match protocols {
+            Protocols::Http1 => Some(true),
+            Protocols::Http2 => Some(false),
+            Protocols::Auto => None
+        }
+    };
+
+    let service = service_fn(move |mut req: Request<body::Incoming>| {
+        let router = router.clone();
+        let alt_svc = alt_svc.clone();
+        let request = RequestPhase::start(phase.clone());
+        req.extensions_mut().insert(conn.clone());
+
+        async move {
+            let mut response = router.call(req).await?;
+            if let Some(alt_svc) = alt_svc {
+                response.headers_mut().insert(header::ALT_SVC, alt_svc);
+            }
+            Ok::<_, Infallible>(response.map(|body| PhaseBody { body, _request: request }))
+        }
+    });
+
+    // hyper's header_read_timeout isn't used, as it also runs while waiting
+    // for the next request, cutting keep-alive short
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    if let Some(max) = limits.max_header_size {
+        builder.http1().max_buf_size(max);
+        builder.http2().max_header_list_size(max.try_into().unwrap_or(u32::MAX));
+    }
+
+    let builder = match protocols {
+        Protocols::Http1 => builder.http1_only(),
+        Protocols::Http2 => builder.http2_only(),
+        Protocols::Auto => builder
+    };
+
+    let connection = builder.serve_connection(TokioIo::new(io), service);
+    tokio::pin!(connection);
+
+    // responses still being sent are finished before closing
+    let result = tokio::select! {
+        result = connection.as_mut() => result,
+        _ = connections.shutting_down() => {
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        },
+        phase = timed_out => {
+            if phase == Phase::Headers {
+                println!("Closed connection, no request within {}s", limits.header_timeout.as_secs());
+                return;
+            }
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+
+    if let Err(err) = result {
+        println!("Failed to serve connection: {:?}", err);
+    }
+}
+
+/// Where a connection is at, for its timeouts
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Phase {
+    /// Waiting for the client to send a request
+    Headers,
+    /// Handling this many requests
+    Requests(usize),
+    /// Kept alive, without requests
+    Idle
+}
+
+impl Phase {
+    fn started(self) -> Self {
+        match self {
+            Phase::Requests(count) => Phase::Requests(count + 1),
+            _ => Phase::Requests(1)
+        }
+    }
+
+    fn finished(self) -> Self {
+        match self {
+            Phase::Requests(count) if count > 1 => Phase::Requests(count - 1),
+            _ => Phase::Idle
+        }
+    }
+}
+
+/// A request counted in its connection's phase, until dropped
+struct RequestPhase(Arc<watch::Sender<Phase>>);
+
+impl RequestPhase {
+    fn start(phase: Arc<watch::Sender<Phase>>) -> Self {
+        phase.send_modify(|phase| *phase = phase.started());
+        RequestPhase(phase)
+    }
+}
+
+impl Drop for RequestPhase {
+    fn drop(&mut self) {
+        self.0.send_modify(|phase| *phase = phase.finished());
+    }
+}
+
+/// Response body holding its request's phase, so a streamed response counts
+/// as in progress until it's sent, or the client goes away
+struct PhaseBody<B> {
+    body: B,
+    _request: RequestPhase
+}
+
+impl<B: Body + Unpin> Body for PhaseBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Frame<B::Data>, B::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_frame(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+/// Resolves with the phase a connection spent too long in, the sender must be
+/// kept alive by the caller
+async fn timed_out(mut phase: watch::Receiver<Phase>, limits: &ConnectionLimits) -> Phase {
+    loop {
+        let current = *phase.borrow_and_update();
+        let timeout = match current {
+            Phase::Headers => limits.header_timeout,
+            Phase::Idle => limits.keepalive_timeout,
+            Phase::Requests(_) => {
+                let _ = phase.changed().await;
+                continue;
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(timeout) => return current,
+            _ = phase.changed() => {}
+        }
+    }
+}
+
+/// Connection IO that moves an idle HTTP/1 connection to `Phase::Headers`
+/// once the client starts sending its next request. HTTP/2 clients send
+/// frames while idle, so their connections stay idle until a request.
+struct PhaseIo<I> {
+    io: I,
+    phase: Arc<watch::Sender<Phase>>,
+    /// Whether the connection is HTTP/1, `None` until the client's first bytes
+    /// tell whether it's the HTTP/2 preface
+    http1: Option<bool>
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for PhaseIo<I> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut this.io).poll_read(cx, buf);
+
+        let read = &buf.filled()[filled..];
+        if !read.is_empty() && *this.http1.get_or_insert_with(|| !read.starts_with(b"PRI ")) {
+            this.phase.send_if_modified(|phase| {
+                let idle = *phase == Phase::Idle;
+                if idle {
+                    *phase = Phase::Headers;
+                }
+                idle
+            });
+        }
+
+        poll
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for PhaseIo<I> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>]
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::listener::SocketFile;
+    use http_body_util::{BodyExt, Empty};
+    use hyper::{body::Bytes, client::conn::{http1, http2}, StatusCode};
+    use shell_serve::{
+        route::{Route, RouteOptions}, router::RouterConfig, tls::{CertPair, ClientAuth, ClientCa}
+    };
+    use bytes::Buf;
+    use std::path::{Path, PathBuf};
+    use tokio::net::UnixStream;
+
+    fn listener_config(dir: &Path) -> ListenerConfig {
+        ListenerConfig {
+            listen: ListenAddr::Unix(dir.join("http.sock")),
+            port: 0,
+            socket_file: SocketFile::default(),
+            tls_certs: vec![],
+            tls_client_ca: None,
+            h2c: false,
+            http3: false,
+            proxy_protocol: false,
+            tags: None,
+            fd_name: None
+        }
+    }
+
+    fn limits() -> ConnectionLimits {
+        ConnectionLimits {
+            header_timeout: Duration::from_secs(5),
+            keepalive_timeout: Duration::from_secs(5),
+            max_header_size: None,
+            max_connections: None,
+            over_limit: OverLimit::Queue
+        }
+    }
+
+    /// Serve `routes` on `config` until the returned connections shut down
+    async fn serve(config: ListenerConfig, routes: Vec<Route>) -> Connections {
+        let router = ShellRouter::new(routes, RouterConfig::default());
+        let server = Server::bind(config, router, None).await.unwrap();
+        let connections = Connections::new(limits());
+        tokio::spawn(server.serve(connections.clone()));
+        connections
+    }
+
+    /// Self-signed certificate for `names`, written to files named after the
+    /// first one, and its DER for clients
+    fn certificate(dir: &Path, names: &[&str]) -> (CertPair, rustls::pki_types::CertificateDer<'static>) {
+        let cert = rcgen::generate_simple_self_signed(names.iter().map(|name| name.to_string()).collect::<Vec<_>>())
+            .unwrap();
+        let pair = CertPair { cert: dir.join(format!("{}.pem", names[0])), key: dir.join(format!("{}.key", names[0])) };
+        std::fs::write(&pair.cert, cert.cert.pem()).unwrap();
+        std::fs::write(&pair.key, cert.key_pair.serialize_pem()).unwrap();
+        (pair, cert.cert.der().clone())
+    }
+
+    /// CA bundle in `dir`, and a client config trusting `server` with a
+    /// certificate for `deploy-bot` signed by the CA
+    fn client_certificate(
+        dir: &Path,
+        server: rustls::pki_types::CertificateDer<'static>
+    ) -> (PathBuf, rustls::ClientConfig) {
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        params.distinguished_name.push(rcgen::DnType::CommonName, "Test CA");
+        let ca = params.self_signed(&ca_key).unwrap();
+        let bundle = dir.join("ca.pem");
+        std::fs::write(&bundle, ca.pem()).unwrap();
+
+        let key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+        params.distinguished_name.push(rcgen::DnType::CommonName, "deploy-bot");
+        params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ClientAuth];
+        let cert = params.signed_by(&key, &ca, &ca_key).unwrap();
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(server).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let key = rustls::pki_types::PrivatePkcs8KeyDer::from(key.serialize_der());
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_client_auth_cert(vec![cert.der().clone()], key.into())
+            .unwrap();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        (bundle, config)
+    }
+
+    /// Client TLS config trusting only `certs`, offering `alpn`
+    fn client_tls(certs: &[rustls::pki_types::CertificateDer<'static>], alpn: &[u8]) -> rustls::ClientConfig {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in certs {
+            roots.add(cert.clone()).unwrap();
+        }
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        config
+    }
+
+    async fn http2_client<I>(io: I) -> http2::SendRequest<Empty<Bytes>>
+        where I: AsyncRead + AsyncWrite + Unpin + Send + 'static
+    {
+        let (sender, conn) = http2::handshake(TokioExecutor::new(), TokioIo::new(io)).await.unwrap();
+        tokio::spawn(conn);
+        sender
+    }
+
+    /// HTTP/3 connection to `port` on loopback, trusting only `cert`
+    async fn http3_client(
+        cert: rustls::pki_types::CertificateDer<'static>,
+        port: u16
+    ) -> (quinn::Connection, h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>) {
+        let crypto = quinn::crypto::rustls::QuicClientConfig::try_from(client_tls(&[cert], http3::ALPN)).unwrap();
+        let mut endpoint = quinn::Endpoint::client("127.0.0.1:0".parse().unwrap()).unwrap();
+        endpoint.set_default_client_config(quinn::ClientConfig::new(Arc::new(crypto)));
+        let conn = endpoint.connect(SocketAddr::from(([127, 0, 0, 1], port)), "localhost").unwrap().await.unwrap();
+
+        let (mut driver, sender) = h3::client::new(h3_quinn::Connection::new(conn.clone())).await.unwrap();
+        tokio::spawn(async move { std::future::poll_fn(|cx| driver.poll_close(cx)).await });
+        (conn, sender)
+    }
+
+    async fn http3_get(
+        sender: &mut h3::client::SendRequest<h3_quinn::OpenStreams, Bytes>,
+        uri: &str
+    ) -> (StatusCode, Vec<u8>) {
+        let mut stream = sender.send_request(Request::get(uri).body(()).unwrap()).await.unwrap();
+        stream.finish().await.unwrap();
+
+        let status = stream.recv_response().await.unwrap().status();
+        let mut body = vec![];
+        while let Some(mut data) = stream.recv_data().await.unwrap() {
+            let len = data.remaining();
+            body.extend_from_slice(&data.copy_to_bytes(len));
+        }
+        (status, body)
+    }
+
+    /// Listener on a free loopback port, serving HTTP/3 on the same UDP port
+    fn http3_config(dir: &Path, pair: CertPair) -> ListenerConfig {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        ListenerConfig {
+            listen: ListenAddr::Ip("127.0.0.1".parse().unwrap()),
+            port,
+            tls_certs: vec![pair],
+            http3: true,
+            ..listener_config(dir)
+        }
+    }
+
+    fn get(uri: &str) -> Request<Empty<Bytes>> {
+        Request::get(uri).header(header::HOST, "localhost").body(Empty::new()).unwrap()
+    }
+
+    /// GET `uri` over TLS on the socket in `dir`, connecting to `name`,
+    /// returning the certificate the server presented
+    async fn tls_get(
+        dir: &Path,
+        config: rustls::ClientConfig,
+        name: &str,
+        uri: &str
+    ) -> (rustls::pki_types::CertificateDer<'static>, StatusCode, String) {
+        let stream = UnixStream::connect(dir.join("http.sock")).await.unwrap();
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect(name.to_string().try_into().unwrap(), stream)
+            .await
+            .unwrap();
+        let cert = stream.get_ref().1.peer_certificates().unwrap()[0].clone();
+
+        let response = http2_client(stream).await.send_request(get(uri)).await.unwrap();
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (cert, status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_h2c() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = ListenerConfig { h2c: true, ..listener_config(dir.path()) };
+        let connections = serve(config, vec!["GET:/ echo hello".parse().unwrap()]).await;
+        let socket = dir.path().join("http.sock");
+
+        // prior knowledge HTTP/2
+        let mut sender = http2_client(UnixStream::connect(&socket).await.unwrap()).await;
+        let response = sender.send_request(get("http://localhost/")).await.unwrap();
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello\n");
+
+        // HTTP/1 still served on the same listener
+        let io = TokioIo::new(UnixStream::connect(&socket).await.unwrap());
+        let (mut sender, conn) = http1::handshake(io).await.unwrap();
+        tokio::spawn(conn);
+        let response = sender.send_request(get("/")).await.unwrap();
+        assert_eq!(response.version(), hyper::Version::HTTP_11);
+        assert_eq!(response.status(), StatusCode::OK);
+
+        connections.shut_down();
+    }
+
+    #[tokio::test]
+    async fn test_alpn_h2() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pair, der) = certificate(dir.path(), &["localhost"]);
+        let config = ListenerConfig { tls_certs: vec![pair], ..listener_config(dir.path()) };
+        let connections = serve(config, vec!["GET:/ echo hello".parse().unwrap()]).await;
+
+        let client_config = client_tls(&[der], b"h2");
+
+        let stream = UnixStream::connect(dir.path().join("http.sock")).await.unwrap();
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(client_config))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+
+        let mut sender = http2_client(stream).await;
+        let response = sender.send_request(get("https://localhost/")).await.unwrap();
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(body, "hello\n");
+
+        connections.shut_down();
+    }
+
+    #[tokio::test]
+    async fn test_tls_certificates() {
+        let dir = tempfile::tempdir().unwrap();
+        let (default_pair, default_der) = certificate(dir.path(), &["a.test", "127.0.0.1"]);
+        let (pair, der) = certificate(dir.path(), &["b.test"]);
+        let handler = dir.path().join("tls.sh");
+        std::fs::write(&handler, "#!/bin/sh\necho $HTTPS $SSL_PROTOCOL $SSL_TLS_SNI\n").unwrap();
+        std::fs::set_permissions(&handler, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let config = ListenerConfig { tls_certs: vec![default_pair, pair.clone()], ..listener_config(dir.path()) };
+        let route = format!("GET:/ {}", handler.display()).parse().unwrap();
+        let connections = serve(config, vec![route]).await;
+
+        let client = || client_tls(&[default_der.clone(), der.clone()], b"h2");
+
+        // picked by SNI name, the handler sees the TLS parameters
+        let (cert, status, body) = tls_get(dir.path(), client(), "b.test", "https://b.test/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(cert, der);
+        assert_eq!(body, "on TLSv1.3 b.test\n");
+
+        let (cert, _, body) = tls_get(dir.path(), client(), "a.test", "https://a.test/").await;
+        assert_eq!(cert, default_der);
+        assert_eq!(body, "on TLSv1.3 a.test\n");
+
+        // no SNI name for IP addresses, the first certificate is the default
+        let (cert, _, body) = tls_get(dir.path(), client(), "127.0.0.1", "https://127.0.0.1/").await;
+        assert_eq!(cert, default_der);
+        assert_eq!(body, "on TLSv1.3\n");
+
+        // rewritten certificate files are served after a reload
+        let (_, reloaded) = certificate(dir.path(), &["b.test"]);
+        assert_ne!(reloaded, der);
+        nix::sys::signal::raise(nix::sys::signal::Signal::SIGHUP).unwrap();
+        let start = std::time::Instant::now();
+        loop {
+            let client = client_tls(&[der.clone(), reloaded.clone()], b"h2");
+            let (cert, _, _) = tls_get(dir.path(), client, "b.test", "https://b.test/").await;
+            if cert == reloaded {
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "rewritten certificate should be reloaded");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        connections.shut_down();
+    }
+
+    #[tokio::test]
+    async fn test_client_certificates() {
+        for auth in [ClientAuth::Require, ClientAuth::Optional] {
+            let dir = tempfile::tempdir().unwrap();
+            let (pair, der) = certificate(dir.path(), &["localhost"]);
+            let (bundle, with_cert) = client_certificate(dir.path(), der.clone());
+            let handler = dir.path().join("cert.sh");
+            std::fs::write(&handler, "#!/bin/sh\necho $SSL_CLIENT_VERIFY $SSL_CLIENT_S_DN_CN\n").unwrap();
+            std::fs::set_permissions(&handler, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+            let route = |path: &str, client_cert: Option<&str>| {
+                let options = RouteOptions {
+                    client_cert: client_cert.map(|c| vec![c.parse().unwrap()]),
+                    ..Default::default()
+                };
+                format!("GET:{path} {}", handler.display()).parse::<Route>().unwrap().with_options(options)
+            };
+            let routes = vec![
+                route("/", None),
+                route("/deploy", Some("CN=deploy-bot")),
+                route("/other", Some("CN=other"))
+            ];
+            let config = ListenerConfig {
+                tls_certs: vec![pair],
+                tls_client_ca: Some(ClientCa { bundle, auth }),
+                ..listener_config(dir.path())
+            };
+            let connections = serve(config, routes).await;
+
+            // the verified certificate reaches handlers, and route conditions
+            let (_, status, body) = tls_get(dir.path(), with_cert.clone(), "localhost", "https://localhost/").await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(body, "SUCCESS deploy-bot\n");
+            let (_, status, _) = tls_get(dir.path(), with_cert.clone(), "localhost", "https://localhost/deploy").await;
+            assert_eq!(status, StatusCode::OK);
+            let (_, status, _) = tls_get(dir.path(), with_cert, "localhost", "https://localhost/other").await;
+            assert_eq!(status, StatusCode::FORBIDDEN);
+
+            let without_cert = || client_tls(std::slice::from_ref(&der), b"h2");
+            match auth {
+                ClientAuth::Require => {
+                    // TLS 1.3 clients learn about the rejection on their first read
+                    let stream = UnixStream::connect(dir.path().join("http.sock")).await.unwrap();
+                    let stream = tokio_rustls::TlsConnector::from(Arc::new(without_cert()))
+                        .connect("localhost".try_into().unwrap(), stream)
+                        .await;
+                    if let Ok(mut stream) = stream {
+                        let read = tokio::io::AsyncReadExt::read(&mut stream, &mut [0; 1]).await;
+                        assert!(read.is_err(), "clients without a certificate should be rejected");
+                    }
+                },
+                ClientAuth::Optional => {
+                    let (_, status, body) = tls_get(dir.path(), without_cert(), "localhost", "https://localhost/").await;
+                    assert_eq!(status, StatusCode::OK);
+                    assert_eq!(body, "NONE\n");
+                    let (_, status, _) = tls_get(dir.path(), without_cert(), "localhost", "https://localhost/deploy").await;
+                    assert_eq!(status, StatusCode::FORBIDDEN);
+                }
+            }
+
+            connections.shut_down();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_streamed_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let handler = dir.path().join("events.sh");
+        std::fs::write(&handler, "#!/bin/sh\necho first\nsleep 1\necho second\n").unwrap();
+        std::fs::set_permissions(&handler, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let route = |path: &str, stream: bool| {
+            let options = RouteOptions { stream, ..Default::default() };
+            format!("GET:{path} {}", handler.display()).parse::<Route>().unwrap().with_options(options)
+        };
+        let config = ListenerConfig { h2c: true, ..listener_config(dir.path()) };
+        let connections = serve(config, vec![route("/stream", true), route("/wait", false)]).await;
+        let mut sender = http2_client(UnixStream::connect(dir.path().join("http.sock")).await.unwrap()).await;
+
+        // first output arrives while the handler still runs
+        let response = sender.send_request(get("http://localhost/stream")).await.unwrap();
+        let mut body = response.into_body();
+        let frame = tokio::time::timeout(Duration::from_millis(700), body.frame()).await
+            .expect("output should be streamed before the handler exits");
+        assert_eq!(frame.unwrap().unwrap().into_data().unwrap(), "first\n");
+        assert_eq!(body.collect().await.unwrap().to_bytes(), "second\n");
+
+        // without `stream`, the response waits for the handler to exit
+        let start = std::time::Instant::now();
+        let response = sender.send_request(get("http://localhost/wait")).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert_eq!(response.into_body().collect().await.unwrap().to_bytes(), "first\nsecond\n");
+
+        connections.shut_down();
+    }
+
+    #[tokio::test]
+    async fn test_http3() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pair, der) = certificate(dir.path(), &["localhost"]);
+
+        let config = http3_config(dir.path(), pair);
+        let port = config.port;
+        let connections = serve(config, vec!["GET:/ echo hello".parse().unwrap()]).await;
+
+        let (conn, mut sender) = http3_client(der.clone(), port).await;
+        let (status, body) = http3_get(&mut sender, "https://localhost/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"hello\n");
+
+        // QUIC connections count as open, for the idle timeout and draining
+        assert_eq!(*connections.count.borrow(), 1);
+        conn.close(0u32.into(), b"");
+        tokio::time::timeout(Duration::from_secs(5), connections.closed()).await
+            .expect("closed QUIC connection should no longer count as open");
+
+        // HTTP/3 is advertised on the TCP listener
+        let stream = tokio::net::TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(client_tls(&[der], b"h2")))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap();
+        let response = http2_client(stream).await.send_request(get("https://localhost/")).await.unwrap();
+        assert_eq!(response.headers()[header::ALT_SVC], format!("h3=\":{port}\"; ma=86400").as_str());
+
+        connections.shut_down();
+    }
+
+    #[tokio::test]
+    async fn test_http3_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let (pair, der) = certificate(dir.path(), &["localhost"]);
+        let config = http3_config(dir.path(), pair);
+        let port = config.port;
+        let handler = dir.path().join("slow.sh");
+        std::fs::write(&handler, "#!/bin/sh\nsleep 0.5\necho done\n").unwrap();
+        std::fs::set_permissions(&handler, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let route = format!("GET:/slow {}", handler.display()).parse().unwrap();
+        let connections = serve(config, vec![route]).await;
+
+        let (conn, mut sender) = http3_client(der, port).await;
+        let request = tokio::spawn(async move { http3_get(&mut sender, "https://localhost/slow").await });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // the request in progress is answered, then the connection is closed
+        connections.shut_down();
+        let (status, body) = request.await.unwrap();
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, b"done\n");
+
+        tokio::time::timeout(Duration::from_secs(5), connections.closed()).await
+            .expect("HTTP/3 connection should be closed once its requests are done");
+        assert!(conn.close_reason().is_some());
+    }
+
+    #[test]
+    fn test_over_limit_from_str() {
+        assert_eq!("queue".parse(), Ok(OverLimit::Queue));
+        assert_eq!("reject".parse(), Ok(OverLimit::Reject));
+        assert!("drop".parse::<OverLimit>().is_err());
+        assert_eq!(OverLimit::default(), OverLimit::Queue);
+    }
+
+    #[test]
+    fn test_phase() {
+        let phase = Phase::Headers.started();
+        assert_eq!(phase, Phase::Requests(1));
+        // HTTP/2 requests overlap
+        assert_eq!(phase.started(), Phase::Requests(2));
+        assert_eq!(phase.started().finished(), Phase::Requests(1));
+        assert_eq!(phase.finished(), Phase::Idle);
+        assert_eq!(Phase::Idle.started(), Phase::Requests(1));
+    }
+
+    #[tokio::test]
+    async fn test_timed_out() {
+        let limits = ConnectionLimits {
+            header_timeout: Duration::from_millis(100),
+            keepalive_timeout: Duration::from_millis(200),
+            ..limits()
+        };
+        let phase = watch::Sender::new(Phase::Headers);
+
+        let start = std::time::Instant::now();
+        assert_eq!(timed_out(phase.subscribe(), &limits).await, Phase::Headers);
+        assert!(start.elapsed() >= limits.header_timeout);
+
+        // no timeout while requests are handled
+        phase.send_replace(Phase::Requests(1));
+        let timeout = tokio::time::timeout(Duration::from_millis(300), timed_out(phase.subscribe(), &limits));
+        assert!(timeout.await.is_err());
+
+        phase.send_replace(Phase::Idle);
+        let start = std::time::Instant::now();
+        assert_eq!(timed_out(phase.subscribe(), &limits).await, Phase::Idle);
+        assert!(start.elapsed() >= limits.keepalive_timeout);
+    }
+
+    #[tokio::test]
+    async fn test_phase_body() {
+        let limits = ConnectionLimits { keepalive_timeout: Duration::from_millis(100), ..limits() };
+        let phase = Arc::new(watch::Sender::new(Phase::Idle));
+        let request = RequestPhase::start(phase.clone());
+        let timed_out = timed_out(phase.subscribe(), &limits);
+        tokio::pin!(timed_out);
+
+        // a response streamed for longer than the keep-alive timeout
+        let frames = futures_util::stream::unfold(0, |sent| async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            (sent < 3).then(|| (Ok::<_, Infallible>(Frame::data(Bytes::from("data\n"))), sent + 1))
+        });
+        let body = PhaseBody { body: http_body_util::StreamBody::new(Box::pin(frames)), _request: request };
+
+        tokio::select! {
+            body = body.collect() => assert_eq!(body.unwrap().to_bytes(), "data\ndata\ndata\n"),
+            _ = &mut timed_out => panic!("connection shouldn't time out while the response is sent")
+        }
+        assert_eq!(*phase.borrow(), Phase::Idle);
+        assert_eq!(timed_out.await, Phase::Idle);
+
+        // dropped without being sent, when the client goes away
+        let body = PhaseBody { body: Empty::<Bytes>::new(), _request: RequestPhase::start(phase.clone()) };
+        assert_eq!(*phase.borrow(), Phase::Requests(1));
+        drop(body);
+        assert_eq!(*phase.borrow(), Phase::Idle);
+    }
+}
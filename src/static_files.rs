@@ -0,0 +1,423 @@
+use crate::{
+    route::{expand_params, RouteParams},
+    router::RouterError,
+    router_service::{full_body, stream_body, ServiceResponse},
+    Error
+};
+use hyper::{header, http::request, HeaderMap, Response, StatusCode};
+use std::{
+    fs::Metadata, io::{Error as IoError, ErrorKind, SeekFrom},
+    path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}
+};
+use tokio::{fs, io::{AsyncReadExt, AsyncSeekExt}};
+
+
+/// Directory served by a static route
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StaticDir {
+    /// Directory files are served from, may use `${name}` route params
+    pub root: String,
+    /// Files served for a directory, in order of preference
+    pub index: Vec<String>,
+    /// Render HTML listing for directories without an index file
+    pub listing: bool
+}
+
+impl StaticDir {
+    pub fn new(root: String) -> Self {
+        StaticDir { root, index: vec!["index.html".to_string()], listing: false }
+    }
+
+    /// Serve `file_path`, relative to the root, refusing paths that resolve
+    /// outside of the root
+    pub async fn serve(
+        &self,
+        file_path: &str,
+        params: &RouteParams<'_>,
+        req: &request::Parts
+    ) -> Result<ServiceResponse, RouterError> {
+        let root = expand_params(&self.root, params);
+        let root = fs::canonicalize(&root).await
+            .map_err(Error::from)?;
+
+        let not_found = || RouterError::FileNotFound(file_path.to_string());
+
+        let path = relative_path(file_path)
+            .ok_or_else(not_found)?;
+        let (path, meta) = resolve(&root, &root.join(path)).await
+            .map_err(|e| file_error(e, file_path))?
+            .ok_or_else(not_found)?;
+
+        if !meta.is_dir() {
+            return serve_file(&path, &meta, &req.headers).await;
+        }
+
+        let uri_path = req.uri.path();
+        if !uri_path.ends_with('/') {
+            let location = match req.uri.query() {
+                Some(query) => format!("{uri_path}/?{query}"),
+                None => format!("{uri_path}/")
+            };
+
+            return Ok(Response::builder()
+                .status(StatusCode::MOVED_PERMANENTLY)
+                .header(header::LOCATION, location)
+                .body(full_body(""))
+                .unwrap());
+        }
+
+        for index in &self.index {
+            if let Ok(Some((path, meta))) = resolve(&root, &path.join(index)).await {
+                if meta.is_file() {
+                    return serve_file(&path, &meta, &req.headers).await;
+                }
+            }
+        }
+
+        if self.listing {
+            return listing(&path, uri_path).await;
+        }
+
+        Err(not_found())
+    }
+}
+
+/// Serve file named by a handler in `X-Sendfile`, which must be under one
+/// of the `allowed` directories
+pub(crate) async fn send_file(path: &str, allowed: &[PathBuf], headers: &HeaderMap) -> Result<ServiceResponse, RouterError> {
+    // don't reveal server paths in error responses
+    let name = Path::new(path).file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let resolved = fs::canonicalize(path).await
+        .map_err(|e| file_error(e, &name))?;
+
+    let mut is_allowed = false;
+    for dir in allowed {
+        if fs::canonicalize(dir).await.is_ok_and(|dir| resolved.starts_with(dir)) {
+            is_allowed = true;
+            break;
+        }
+    }
+
+    if !is_allowed {
+        return Err(Error::InvalidHeader(format!("sendfile path '{path}' is outside of allowed dirs")).into());
+    }
+
+    let meta = fs::metadata(&resolved).await
+        .map_err(|e| file_error(e, &name))?;
+    if !meta.is_file() {
+        return Err(RouterError::FileNotFound(name));
+    }
+
+    serve_file(&resolved, &meta, headers).await
+}
+
+/// Percent-decode request path, `None` if it has `..` segments
+fn relative_path(file_path: &str) -> Option<PathBuf> {
+    let decoded = urlparse::unquote(file_path).ok()?;
+
+    let mut path = PathBuf::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => return None,
+            s if s.contains('\0') => return None,
+            s => path.push(s)
+        }
+    }
+
+    Some(path)
+}
+
+/// Canonical path and metadata, `None` if the path resolves outside of `root`,
+/// e.g. through a symlink
+async fn resolve(root: &Path, path: &Path) -> Result<Option<(PathBuf, Metadata)>, IoError> {
+    let path = fs::canonicalize(path).await?;
+    if !path.starts_with(root) {
+        return Ok(None);
+    }
+
+    let meta = fs::metadata(&path).await?;
+    Ok(Some((path, meta)))
+}
+
+fn file_error(err: IoError, file_path: &str) -> RouterError {
+    match err.kind() {
+        ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::NotADirectory =>
+            RouterError::FileNotFound(file_path.to_string()),
+        _ => Error::from(err).into()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    Full,
+    /// First and last byte, inclusive
+    Partial(u64, u64),
+    Unsatisfiable
+}
+
+/// Parse `Range` header for a file of `len` bytes, invalid or multiple
+/// ranges are ignored and the full file is sent
+fn parse_range(range: &str, len: u64) -> ByteRange {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    match (first.parse::<u64>(), last.parse::<u64>()) {
+        // suffix range, e.g. `-500` for the last 500 bytes
+        (Err(_), Ok(suffix)) if first.is_empty() => {
+            if suffix == 0 || len == 0 {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(len.saturating_sub(suffix), len - 1)
+            }
+        },
+        (Ok(first), Err(_)) if last.is_empty() => {
+            if first >= len {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(first, len - 1)
+            }
+        },
+        (Ok(first), Ok(last)) if first <= last => {
+            if first >= len {
+                ByteRange::Unsatisfiable
+            } else {
+                ByteRange::Partial(first, last.min(len - 1))
+            }
+        },
+        _ => ByteRange::Full
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    headers.get(name)
+        .and_then(|v| v.to_str().ok())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Conditional request headers match current file, so `304 Not Modified`
+/// can be sent, `If-None-Match` takes precedence over `If-Modified-Since`
+fn not_modified(headers: &HeaderMap, etag: &str, modified: SystemTime) -> bool {
+    if let Some(if_none_match) = header_str(headers, header::IF_NONE_MATCH) {
+        return if_none_match.split(',')
+            .map(|t| t.trim())
+            .any(|t| t == "*" || t.trim_start_matches("W/") == etag);
+    }
+
+    header_str(headers, header::IF_MODIFIED_SINCE)
+        .and_then(|d| httpdate::parse_http_date(d).ok())
+        .is_some_and(|since| unix_secs(modified) <= unix_secs(since))
+}
+
+/// `Range` applies, when `If-Range` is missing or matches current file
+fn if_range_matches(headers: &HeaderMap, etag: &str, modified: SystemTime) -> bool {
+    match header_str(headers, header::IF_RANGE) {
+        None => true,
+        Some(tag) if tag.starts_with('"') => tag == etag,
+        Some(date) => httpdate::parse_http_date(date)
+            .is_ok_and(|date| unix_secs(date) == unix_secs(modified))
+    }
+}
+
+async fn serve_file(path: &Path, meta: &Metadata, headers: &HeaderMap) -> Result<ServiceResponse, RouterError> {
+    let len = meta.len();
+    let modified = meta.modified()
+        .map_err(Error::from)?;
+
+    let mtime = modified.duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let etag = format!("\"{:x}-{:x}\"", mtime.as_nanos(), len);
+
+    let builder = Response::builder()
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, httpdate::fmt_http_date(modified))
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    if not_modified(headers, &etag, modified) {
+        return Ok(builder.status(StatusCode::NOT_MODIFIED)
+            .body(full_body(""))
+            .unwrap());
+    }
+
+    let range = match header_str(headers, header::RANGE) {
+        Some(range) if if_range_matches(headers, &etag, modified) => parse_range(range, len),
+        _ => ByteRange::Full
+    };
+
+    let content_type = mime_guess::from_path(path)
+        .first_or_octet_stream();
+    let builder = builder.header(header::CONTENT_TYPE, content_type.as_ref());
+
+    let mut file = fs::File::open(path).await
+        .map_err(Error::from)?;
+
+    let response = match range {
+        ByteRange::Full => builder.status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, len)
+            .body(stream_body(file.take(len))),
+        ByteRange::Partial(first, last) => {
+            file.seek(SeekFrom::Start(first)).await
+                .map_err(Error::from)?;
+
+            let part_len = last - first + 1;
+            builder.status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {first}-{last}/{len}"))
+                .header(header::CONTENT_LENGTH, part_len)
+                .body(stream_body(file.take(part_len)))
+        },
+        ByteRange::Unsatisfiable => builder.status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{len}"))
+            .body(full_body(""))
+    };
+
+    Ok(response.unwrap())
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+async fn listing(dir: &Path, uri_path: &str) -> Result<ServiceResponse, RouterError> {
+    let mut names = vec![];
+
+    let mut entries = fs::read_dir(dir).await
+        .map_err(Error::from)?;
+    while let Some(entry) = entries.next_entry().await.map_err(Error::from)? {
+        let mut name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+            name.push('/');
+        }
+        names.push(name);
+    }
+    names.sort();
+
+    let title = escape_html(&urlparse::unquote(uri_path).unwrap_or_else(|_| uri_path.to_string()));
+
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n\
+        <body>\n<h1>Index of {title}</h1>\n<ul>\n"
+    );
+    if uri_path != "/" {
+        html.push_str("<li><a href=\"../\">../</a></li>\n");
+    }
+    for name in names {
+        let href = urlparse::quote(&name, b"/").unwrap_or_default();
+        html.push_str(&format!("<li><a href=\"{}\">{}</a></li>\n", escape_html(&href), escape_html(&name)));
+    }
+    html.push_str("</ul>\n</body>\n</html>\n");
+
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(full_body(html))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use hyper::Request;
+
+    async fn get(dir: &StaticDir, path: &str, headers: &[(header::HeaderName, &str)]) -> (StatusCode, HeaderMap, String) {
+        let mut req = Request::builder().uri(path);
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        let (parts, _) = req.body(()).unwrap().into_parts();
+
+        let response = dir.serve(path, &vec![], &parts).await.unwrap();
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.unwrap().to_bytes();
+        (parts.status, parts.headers, String::from_utf8_lossy(&body).to_string())
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Partial(0, 99));
+        assert_eq!(parse_range("bytes=900-", 1000), ByteRange::Partial(900, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Partial(900, 999));
+        assert_eq!(parse_range("bytes=-2000", 1000), ByteRange::Partial(0, 999));
+        assert_eq!(parse_range("bytes=500-5000", 1000), ByteRange::Partial(500, 999));
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-6", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=5-1", 1000), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(relative_path("css/site%20main.css"), Some(PathBuf::from("css/site main.css")));
+        assert_eq!(relative_path("/./a//b"), Some(PathBuf::from("a/b")));
+        assert_eq!(relative_path("a/../../etc/passwd"), None);
+        assert_eq!(relative_path("a/%2e%2e/b"), None);
+    }
+
+    #[tokio::test]
+    async fn test_serve_conditional() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a.txt"), "0123456789").unwrap();
+        let dir = StaticDir::new(root.path().to_string_lossy().to_string());
+
+        let (status, headers, body) = get(&dir, "/a.txt", &[]).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "0123456789"));
+        assert_eq!(headers[header::CONTENT_TYPE], "text/plain");
+        let etag = headers[header::ETAG].to_str().unwrap().to_string();
+        let modified = headers[header::LAST_MODIFIED].to_str().unwrap().to_string();
+
+        let (status, _, body) = get(&dir, "/a.txt", &[(header::IF_NONE_MATCH, &etag)]).await;
+        assert_eq!((status, body.as_str()), (StatusCode::NOT_MODIFIED, ""));
+        let (status, _, _) = get(&dir, "/a.txt", &[(header::IF_NONE_MATCH, "\"other\"")]).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _, _) = get(&dir, "/a.txt", &[(header::IF_MODIFIED_SINCE, &modified)]).await;
+        assert_eq!(status, StatusCode::NOT_MODIFIED);
+        let (status, _, _) = get(&dir, "/a.txt", &[(header::IF_MODIFIED_SINCE, "Thu, 01 Jan 1970 00:00:00 GMT")]).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_serve_range() {
+        let root = tempfile::tempdir().unwrap();
+        std::fs::write(root.path().join("a.txt"), "0123456789").unwrap();
+        let dir = StaticDir::new(root.path().to_string_lossy().to_string());
+        let (_, headers, _) = get(&dir, "/a.txt", &[]).await;
+        let etag = headers[header::ETAG].to_str().unwrap().to_string();
+
+        let (status, headers, body) = get(&dir, "/a.txt", &[(header::RANGE, "bytes=2-4")]).await;
+        assert_eq!((status, body.as_str()), (StatusCode::PARTIAL_CONTENT, "234"));
+        assert_eq!(headers[header::CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(headers[header::CONTENT_LENGTH], "3");
+
+        let (status, _, body) = get(&dir, "/a.txt", &[(header::RANGE, "bytes=-3"), (header::IF_RANGE, &etag)]).await;
+        assert_eq!((status, body.as_str()), (StatusCode::PARTIAL_CONTENT, "789"));
+
+        // changed file, whole file is sent
+        let (status, _, body) = get(&dir, "/a.txt", &[(header::RANGE, "bytes=-3"), (header::IF_RANGE, "\"old\"")]).await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "0123456789"));
+
+        let (status, headers, _) = get(&dir, "/a.txt", &[(header::RANGE, "bytes=10-")]).await;
+        assert_eq!(status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(headers[header::CONTENT_RANGE], "bytes */10");
+    }
+}
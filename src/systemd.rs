@@ -0,0 +1,238 @@
+use nix::{
+    fcntl::{fcntl, FcntlArg, FdFlag},
+    time::{clock_gettime, ClockId}
+};
+use std::{
+    env, io,
+    os::{fd::{FromRawFd, OwnedFd, RawFd}, unix::net::UnixDatagram},
+    time::Duration
+};
+
+
+/// First socket passed by socket activation, after stdin, stdout and stderr
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Service manager integration, sockets passed by systemd socket activation
+/// and `sd_notify` status messages. Does nothing when not run by systemd.
+#[derive(Debug, Default)]
+pub struct Systemd {
+    /// Path of the notify socket, abstract when starting with `@`
+    notify_socket: Option<String>,
+    /// Time after which systemd considers the service hung without a ping
+    watchdog: Option<Duration>,
+    /// Passed sockets not yet taken, with their `FileDescriptorName=`
+    sockets: Vec<(String, OwnedFd)>
+}
+
+impl Systemd {
+    /// Read the state passed by systemd, and take its vars out of the env so
+    /// handlers don't inherit them. Changes the env, so must be called before
+    /// any other threads are started.
+    pub fn from_env() -> io::Result<Self> {
+        let mut systemd = Systemd {
+            notify_socket: env::var("NOTIFY_SOCKET").ok(),
+            ..Default::default()
+        };
+
+        if for_this_process("WATCHDOG_PID", true) {
+            systemd.watchdog = env::var("WATCHDOG_USEC").ok()
+                .and_then(|usec| usec.parse().ok())
+                .map(Duration::from_micros);
+        }
+
+        if for_this_process("LISTEN_PID", false) {
+            let count: RawFd = env::var("LISTEN_FDS").ok()
+                .and_then(|count| count.parse().ok())
+                .unwrap_or_default();
+            let fds = (LISTEN_FDS_START..LISTEN_FDS_START + count)
+                .map(|fd| {
+                    // handlers shouldn't inherit the sockets either
+                    fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+                    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+                })
+                .collect::<io::Result<Vec<_>>>()?;
+
+            systemd.sockets = name_sockets(fds, &env::var("LISTEN_FDNAMES").unwrap_or_default());
+        }
+
+        for var in ["NOTIFY_SOCKET", "WATCHDOG_PID", "WATCHDOG_USEC", "LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+            env::remove_var(var);
+        }
+
+        Ok(systemd)
+    }
+
+    /// Take passed socket named `name`, or without a name the first one left
+    pub fn take_socket(&mut self, name: Option<&str>) -> Option<OwnedFd> {
+        let index = match name {
+            Some(name) => self.sockets.iter().position(|(n, _)| n == name)?,
+            None if self.sockets.is_empty() => return None,
+            None => 0
+        };
+        Some(self.sockets.remove(index).1)
+    }
+
+    /// Names of passed sockets no listener has taken
+    pub fn unused_sockets(&self) -> impl Iterator<Item = &str> {
+        self.sockets.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Send `sd_notify` state, e.g. `READY=1`, failures are only logged
+    pub fn notify(&self, state: &str) {
+        let Some(path) = &self.notify_socket else {
+            return;
+        };
+
+        if let Err(e) = send_notify(path, state) {
+            println!("Failed to notify systemd: {e}");
+        }
+    }
+
+    /// Notify start of reload, systemd expects `READY=1` once done
+    pub fn reloading(&self) {
+        let now = clock_gettime(ClockId::CLOCK_MONOTONIC)
+            .map(|time| Duration::from(time).as_micros())
+            .unwrap_or_default();
+        self.notify(&format!("RELOADING=1\nMONOTONIC_USEC={now}"));
+    }
+
+    /// Send watchdog pings at half the watchdog timeout, never resolves
+    pub async fn watchdog(&self) {
+        let Some(timeout) = self.watchdog else {
+            return std::future::pending().await;
+        };
+
+        let mut interval = tokio::time::interval(timeout / 2);
+        loop {
+            interval.tick().await;
+            self.notify("WATCHDOG=1");
+        }
+    }
+}
+
+/// PID in `var` is this process, or `var` is unset and `unset_matches`
+fn for_this_process(var: &str, unset_matches: bool) -> bool {
+    match env::var(var) {
+        Ok(pid) => pid.parse() == Ok(std::process::id()),
+        Err(_) => unset_matches
+    }
+}
+
+/// Pair passed sockets with their names from `LISTEN_FDNAMES`, in order
+fn name_sockets(fds: Vec<OwnedFd>, names: &str) -> Vec<(String, OwnedFd)> {
+    let mut names = names.split(':');
+    fds.into_iter()
+        .map(|fd| (names.next().unwrap_or("unknown").to_string(), fd))
+        .collect()
+}
+
+fn send_notify(path: &str, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    match path.strip_prefix('@') {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            socket.send_to_addr(state.as_bytes(), &SocketAddr::from_abstract_name(name)?)?;
+        },
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => return Err(io::Error::new(io::ErrorKind::Unsupported, "abstract notify socket")),
+        None => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::fd::AsRawFd;
+
+    /// Systemd notifying a datagram socket bound in `dir`
+    fn notified(dir: &std::path::Path) -> (Systemd, UnixDatagram) {
+        let path = dir.join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        let systemd = Systemd { notify_socket: Some(path.to_str().unwrap().to_string()), ..Default::default() };
+        (systemd, socket)
+    }
+
+    fn recv(socket: &UnixDatagram) -> String {
+        let mut buf = [0; 256];
+        let len = socket.recv(&mut buf).unwrap();
+        String::from_utf8_lossy(&buf[..len]).to_string()
+    }
+
+    #[test]
+    fn test_notify() {
+        let dir = tempfile::tempdir().unwrap();
+        let (systemd, socket) = notified(dir.path());
+
+        systemd.notify("READY=1");
+        assert_eq!(recv(&socket), "READY=1");
+
+        systemd.reloading();
+        let reloading = recv(&socket);
+        let usec = reloading.strip_prefix("RELOADING=1\nMONOTONIC_USEC=").unwrap();
+        assert!(usec.parse::<u128>().unwrap() > 0);
+
+        systemd.notify("STOPPING=1");
+        assert_eq!(recv(&socket), "STOPPING=1");
+
+        // without a notify socket nothing is sent, and nothing fails
+        Systemd::default().notify("READY=1");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_notify_abstract() {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let name = format!("shell-serve-test-{}", std::process::id());
+        let socket = UnixDatagram::bind_addr(&SocketAddr::from_abstract_name(&name).unwrap()).unwrap();
+        let systemd = Systemd { notify_socket: Some(format!("@{name}")), ..Default::default() };
+
+        systemd.notify("READY=1");
+        assert_eq!(recv(&socket), "READY=1");
+    }
+
+    #[tokio::test]
+    async fn test_watchdog() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut systemd, socket) = notified(dir.path());
+        systemd.watchdog = Some(Duration::from_millis(200));
+
+        // pinged right away, then every 100ms
+        let _ = tokio::time::timeout(Duration::from_millis(150), systemd.watchdog()).await;
+        assert_eq!(recv(&socket), "WATCHDOG=1");
+        assert_eq!(recv(&socket), "WATCHDOG=1");
+
+        // never resolves without a watchdog
+        let systemd = Systemd::default();
+        assert!(tokio::time::timeout(Duration::from_millis(50), systemd.watchdog()).await.is_err());
+    }
+
+    #[test]
+    fn test_take_socket() {
+        let fds = (0..3).map(|_| OwnedFd::from(UnixDatagram::unbound().unwrap())).collect::<Vec<_>>();
+        let raw = fds.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+        let take = |systemd: &mut Systemd, name| {
+            systemd.take_socket(name).map(|fd| fd.as_raw_fd())
+        };
+
+        // sockets beyond the names are unknown
+        let mut systemd = Systemd { sockets: name_sockets(fds, "http:https"), ..Default::default() };
+        assert_eq!(systemd.unused_sockets().collect::<Vec<_>>(), ["http", "https", "unknown"]);
+
+        assert_eq!(take(&mut systemd, Some("https")), Some(raw[1]));
+        assert_eq!(take(&mut systemd, Some("https")), None);
+        assert_eq!(take(&mut systemd, Some("admin")), None);
+
+        // without a name, the first one left
+        assert_eq!(take(&mut systemd, None), Some(raw[0]));
+        assert_eq!(systemd.unused_sockets().collect::<Vec<_>>(), ["unknown"]);
+        assert_eq!(take(&mut systemd, None), Some(raw[2]));
+        assert_eq!(take(&mut systemd, None), None);
+    }
+}
@@ -0,0 +1,488 @@
+use crate::{problem::error_chain, Error};
+use rustls::{
+    crypto::{ring as ring_provider, CryptoProvider},
+    pki_types::{CertificateDer, ServerName},
+    server::{ClientHello, ResolvesServerCert, ServerConnection, VerifierBuilderError, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    ProtocolVersion, RootCertStore, ServerConfig
+};
+use std::{
+    fs::File, io::{self, BufReader}, net::{Ipv4Addr, Ipv6Addr}, path::{Path, PathBuf}, str::FromStr,
+    sync::{Arc, RwLock}, time::{Duration, SystemTime}
+};
+use tokio::signal::unix::{signal, SignalKind};
+use webpki::EndEntityCert;
+use x509_parser::{
+    certificate::X509Certificate, extensions::GeneralName, objects::{oid2abbrev, oid_registry}, x509::X509Name
+};
+
+
+/// How often certificate files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Certificate chain and private key, in PEM files
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CertPair {
+    pub cert: PathBuf,
+    pub key: PathBuf
+}
+
+impl CertPair {
+    fn load(&self, provider: &CryptoProvider) -> Result<CertifiedKey, TlsError> {
+        let certs = load_certs(&self.cert)?;
+
+        let mut reader = BufReader::new(File::open(&self.key).map_err(io_error(&self.key))?);
+        let key = rustls_pemfile::private_key(&mut reader)
+            .map_err(io_error(&self.key))?
+            .ok_or_else(|| TlsError::NoPrivateKey(self.key.clone()))?;
+
+        CertifiedKey::from_der(certs, key, provider)
+            .map_err(|e| TlsError::InvalidKey(self.key.clone(), e))
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let modified = |path: &Path| path.metadata().and_then(|m| m.modified()).ok();
+        Some((modified(&self.cert)?, modified(&self.key)?))
+    }
+}
+
+fn io_error(path: &Path) -> impl FnOnce(io::Error) -> TlsError {
+    let path = path.to_path_buf();
+    move |e| TlsError::Io(path, e)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let mut reader = BufReader::new(File::open(path).map_err(io_error(path))?);
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(io_error(path))?;
+
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+/// Whether clients must present a certificate signed by the client CA
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum ClientAuth {
+    #[default]
+    Require,
+    /// Clients without a certificate are let in, routes can still require one
+    Optional
+}
+
+impl FromStr for ClientAuth {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "require" => Ok(ClientAuth::Require),
+            "optional" => Ok(ClientAuth::Optional),
+            _ => Err(Error::InvalidRoute(format!("invalid client auth mode '{s}'")))
+        }
+    }
+}
+
+/// CA bundle that client certificates are verified against
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClientCa {
+    pub bundle: PathBuf,
+    pub auth: ClientAuth
+}
+
+/// Picks the certificate for the SNI name of a connection, the first pair is
+/// the default. Certificates are swapped on reload, connections already
+/// established keep the certificate they were made with.
+#[derive(Debug)]
+pub struct CertResolver {
+    pairs: Vec<CertPair>,
+    provider: Arc<CryptoProvider>,
+    keys: RwLock<Vec<Arc<CertifiedKey>>>
+}
+
+impl CertResolver {
+    pub fn new(pairs: Vec<CertPair>) -> Result<Self, TlsError> {
+        let provider = Arc::new(ring_provider::default_provider());
+        let keys = load_keys(&pairs, &provider)?;
+
+        Ok(CertResolver { pairs, provider, keys: RwLock::new(keys) })
+    }
+
+    /// Server config for TLS 1.2 and 1.3, offering the `alpn` protocols, and
+    /// verifying client certificates when there is a `client_ca`
+    pub fn server_config(
+        self: &Arc<Self>,
+        alpn: &[&[u8]],
+        client_ca: Option<&ClientCa>
+    ) -> Result<ServerConfig, TlsError> {
+        let builder = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = match client_ca {
+            Some(ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(&ca.bundle)? {
+                    roots.add(cert)?;
+                }
+
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), self.provider.clone());
+                let verifier = match ca.auth {
+                    ClientAuth::Require => verifier,
+                    ClientAuth::Optional => verifier.allow_unauthenticated()
+                };
+                builder.with_client_cert_verifier(verifier.build()?)
+            },
+            None => builder.with_no_client_auth()
+        };
+
+        let mut config = builder.with_cert_resolver(self.clone());
+
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        Ok(config)
+    }
+
+    /// Load all certificate pairs again, keeping the current certificates if
+    /// any of them fails to load
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let keys = load_keys(&self.pairs, &self.provider)?;
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    /// Reload certificates on SIGHUP, and when their files change
+    pub fn watch(self: &Arc<Self>) -> io::Result<()> {
+        let mut hangup = signal(SignalKind::hangup())?;
+        let resolver = self.clone();
+
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(WATCH_INTERVAL);
+            let mut modified: Vec<_> = resolver.pairs.iter().map(CertPair::modified).collect();
+
+            loop {
+                let current = tokio::select! {
+                    _ = hangup.recv() => None,
+                    _ = interval.tick() => {
+                        let current: Vec<_> = resolver.pairs.iter().map(CertPair::modified).collect();
+                        if current == modified {
+                            continue;
+                        }
+                        Some(current)
+                    }
+                };
+
+                // a failed reload is retried on the next change, files may
+                // have been caught half written
+                match resolver.reload() {
+                    Ok(()) => {
+                        println!("Reloaded TLS certificates");
+                        if let Some(current) = current {
+                            modified = current;
+                        }
+                    },
+                    Err(e) => println!("Failed to reload TLS certificates, keeping current ones: {}", error_chain(&e))
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let keys = self.keys.read().unwrap();
+
+        let name = client_hello.server_name()
+            .and_then(|name| ServerName::try_from(name).ok());
+
+        name.and_then(|name| keys.iter().find(|key| valid_for_name(key, &name)))
+            .or(keys.first())
+            .cloned()
+    }
+}
+
+fn load_keys(pairs: &[CertPair], provider: &CryptoProvider) -> Result<Vec<Arc<CertifiedKey>>, TlsError> {
+    pairs.iter()
+        .map(|pair| pair.load(provider).map(Arc::new))
+        .collect()
+}
+
+fn valid_for_name(key: &CertifiedKey, name: &ServerName<'_>) -> bool {
+    key.cert.first()
+        .and_then(|cert: &CertificateDer| EndEntityCert::try_from(cert).ok())
+        .is_some_and(|cert| cert.verify_is_valid_for_subject_name(name).is_ok())
+}
+
+/// Negotiated parameters of a TLS connection
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TlsInfo {
+    /// Protocol version, e.g. `TLSv1.3`
+    pub protocol: String,
+    /// Cipher suite, when known
+    pub cipher: Option<String>,
+    /// SNI name sent by the client
+    pub server_name: Option<String>,
+    /// ALPN protocol, e.g. `http/1.1`
+    pub alpn: Option<String>,
+    /// Verified client certificate
+    pub client_cert: Option<ClientCert>
+}
+
+impl TlsInfo {
+    pub fn new(conn: &ServerConnection) -> Self {
+        let protocol = match conn.protocol_version() {
+            Some(ProtocolVersion::TLSv1_2) => "TLSv1.2".to_string(),
+            Some(ProtocolVersion::TLSv1_3) => "TLSv1.3".to_string(),
+            Some(version) => format!("{version:?}"),
+            None => String::new()
+        };
+
+        TlsInfo {
+            protocol,
+            cipher: conn.negotiated_cipher_suite()
+                .map(|suite| format!("{:?}", suite.suite())),
+            server_name: conn.server_name().map(String::from),
+            alpn: conn.alpn_protocol().map(|p| String::from_utf8_lossy(p).to_string()),
+            client_cert: conn.peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| ClientCert::parse(cert))
+        }
+    }
+
+    /// Handler env vars, named after the CGI and mod_ssl ones
+    pub fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("HTTPS", "on".to_string()),
+            ("SSL_PROTOCOL", self.protocol.clone())
+        ];
+
+        if let Some(cipher) = &self.cipher {
+            env.push(("SSL_CIPHER", cipher.clone()));
+        }
+
+        if let Some(name) = &self.server_name {
+            env.push(("SSL_TLS_SNI", name.clone()));
+        }
+
+        if let Some(alpn) = &self.alpn {
+            env.push(("SSL_ALPN", alpn.clone()));
+        }
+
+        match &self.client_cert {
+            Some(cert) => env.extend(cert.env()),
+            None => env.push(("SSL_CLIENT_VERIFY", "NONE".to_string()))
+        }
+
+        env
+    }
+}
+
+/// Identity from a verified client certificate
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ClientCert {
+    /// Subject attributes in certificate order, e.g. `("CN", "deploy-bot")`
+    pub subject: Vec<(String, String)>,
+    pub issuer: Vec<(String, String)>,
+    /// Subject alternative names, e.g. `DNS:bot.example` or `email:bot@example`
+    pub sans: Vec<String>,
+    /// SHA-256 of the certificate, in lower case hex
+    pub fingerprint: String
+}
+
+impl ClientCert {
+    pub fn parse(der: &[u8]) -> Option<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+
+        let fingerprint = ring::digest::digest(&ring::digest::SHA256, der);
+        let fingerprint = fingerprint.as_ref().iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+
+        Some(ClientCert {
+            subject: name_attributes(cert.subject()),
+            issuer: name_attributes(cert.issuer()),
+            sans: subject_alt_names(&cert),
+            fingerprint
+        })
+    }
+
+    /// Subject distinguished name, e.g. `CN=deploy-bot,O=Example`
+    pub fn subject_dn(&self) -> String {
+        distinguished_name(&self.subject)
+    }
+
+    /// Handler env vars, named after the mod_ssl ones
+    fn env(&self) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("SSL_CLIENT_VERIFY", "SUCCESS".to_string()),
+            ("SSL_CLIENT_S_DN", self.subject_dn()),
+            ("SSL_CLIENT_I_DN", distinguished_name(&self.issuer)),
+            ("SSL_CLIENT_SAN", self.sans.join(",")),
+            ("SSL_CLIENT_FINGERPRINT", self.fingerprint.clone())
+        ];
+
+        if let Some((_, cn)) = self.subject.iter().find(|(k, _)| k == "CN") {
+            env.push(("SSL_CLIENT_S_DN_CN", cn.clone()));
+        }
+
+        env
+    }
+}
+
+fn name_attributes(name: &X509Name) -> Vec<(String, String)> {
+    name.iter_attributes()
+        .filter_map(|attr| {
+            let key = oid2abbrev(attr.attr_type(), oid_registry())
+                .map(String::from)
+                .unwrap_or_else(|_| attr.attr_type().to_id_string());
+            Some((key, attr.as_str().ok()?.to_string()))
+        })
+        .collect()
+}
+
+fn distinguished_name(attrs: &[(String, String)]) -> String {
+    attrs.iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn subject_alt_names(cert: &X509Certificate) -> Vec<String> {
+    let Ok(Some(san)) = cert.subject_alternative_name() else {
+        return vec![];
+    };
+
+    san.value.general_names.iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(dns) => Some(format!("DNS:{dns}")),
+            GeneralName::RFC822Name(email) => Some(format!("email:{email}")),
+            GeneralName::URI(uri) => Some(format!("URI:{uri}")),
+            GeneralName::IPAddress(ip) => match ip.len() {
+                4 => Some(format!("IP:{}", Ipv4Addr::from(<[u8; 4]>::try_from(*ip).ok()?))),
+                16 => Some(format!("IP:{}", Ipv6Addr::from(<[u8; 16]>::try_from(*ip).ok()?))),
+                _ => None
+            },
+            _ => None
+        })
+        .collect()
+}
+
+/// Route condition on the client certificate
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ClientCertCondition {
+    /// Any verified certificate, `*`
+    Any,
+    /// Subject has all of the attributes, e.g. `CN=deploy-bot,O=Example`
+    Subject(Vec<(String, String)>),
+    /// Subject alternative name, e.g. `DNS:bot.example`
+    San(String),
+    /// Certificate fingerprint, e.g. `SHA256:ab12...`
+    Fingerprint(String)
+}
+
+impl ClientCertCondition {
+    pub fn matches(&self, cert: &ClientCert) -> bool {
+        match self {
+            ClientCertCondition::Any => true,
+            ClientCertCondition::Subject(attrs) => attrs.iter()
+                .all(|attr| cert.subject.iter().any(|(k, v)| k.eq_ignore_ascii_case(&attr.0) && *v == attr.1)),
+            ClientCertCondition::San(san) => cert.sans.iter()
+                .any(|s| s.eq_ignore_ascii_case(san)),
+            ClientCertCondition::Fingerprint(fingerprint) => cert.fingerprint == *fingerprint
+        }
+    }
+}
+
+impl FromStr for ClientCertCondition {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || Error::InvalidRoute(format!("invalid client certificate condition '{s}'"));
+
+        if s == "*" {
+            return Ok(ClientCertCondition::Any);
+        }
+
+        if let Some((kind, value)) = s.split_once(':') {
+            let kind = kind.to_ascii_lowercase();
+
+            if kind == "sha256" {
+                let fingerprint = value.replace(':', "").to_ascii_lowercase();
+                if fingerprint.len() != 64 || !fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(invalid());
+                }
+                return Ok(ClientCertCondition::Fingerprint(fingerprint));
+            }
+
+            let kind = match kind.as_str() {
+                "dns" => Some("DNS"),
+                "email" => Some("email"),
+                "uri" => Some("URI"),
+                "ip" => Some("IP"),
+                _ => None
+            };
+            if let Some(kind) = kind {
+                return Ok(ClientCertCondition::San(format!("{kind}:{value}")));
+            }
+        }
+
+        let attrs = s.split(',')
+            .map(|attr| {
+                let (k, v) = attr.split_once('=').ok_or_else(invalid)?;
+                Ok((k.trim().to_string(), v.trim().to_string()))
+            })
+            .collect::<Result<_, Error>>()?;
+
+        Ok(ClientCertCondition::Subject(attrs))
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum TlsError {
+    #[error("Failed to read '{}'", .0.display())]
+    Io(PathBuf, #[source] io::Error),
+
+    #[error("No certificate found in '{}'", .0.display())]
+    NoCertificate(PathBuf),
+
+    #[error("No private key found in '{}'", .0.display())]
+    NoPrivateKey(PathBuf),
+
+    #[error("Invalid private key '{}'", .0.display())]
+    InvalidKey(PathBuf, #[source] rustls::Error),
+
+    #[error("TLS config error")]
+    Config(#[from] rustls::Error),
+
+    #[error("Invalid client CA bundle")]
+    ClientCa(#[from] VerifierBuilderError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_cert_condition() {
+        let cert = ClientCert {
+            subject: vec![("CN".to_string(), "deploy-bot".to_string()), ("O".to_string(), "Example".to_string())],
+            issuer: vec![("CN".to_string(), "Example CA".to_string())],
+            sans: vec!["DNS:bot.example".to_string()],
+            fingerprint: "ab".repeat(32)
+        };
+
+        let matches = |condition: &str| condition.parse::<ClientCertCondition>().unwrap().matches(&cert);
+
+        assert!(matches("*"));
+        assert!(matches("CN=deploy-bot"));
+        assert!(matches("cn=deploy-bot, O=Example"));
+        assert!(!matches("CN=deploy-bot,O=Other"));
+        assert!(matches("dns:bot.example"));
+        assert!(!matches("email:bot@example"));
+        assert!(matches(&format!("SHA256:{}", ["AB"; 32].join(":"))));
+
+        assert!("SHA256:abc".parse::<ClientCertCondition>().is_err());
+        assert!("deploy-bot".parse::<ClientCertCondition>().is_err());
+    }
+}
@@ -0,0 +1,76 @@
+use nix::{sys::signal::{kill, Signal}, unistd::Pid};
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::Path,
+    process::{Child, Command, ExitStatus},
+    thread,
+    time::{Duration, Instant}
+};
+
+
+/// Run shell-serve on a Unix socket in `dir`, serving `route`
+fn start(dir: &Path, route: &str) -> Child {
+    let socket = dir.join("http.sock");
+    let child = Command::new(env!("CARGO_BIN_EXE_shell-serve"))
+        .arg("--listen").arg(format!("unix:{}", socket.display()))
+        .args(["--drain-timeout", "1", route])
+        .spawn()
+        .unwrap();
+
+    let start = Instant::now();
+    while !socket.exists() {
+        assert!(start.elapsed() < Duration::from_secs(10), "shell-serve should be listening");
+        thread::sleep(Duration::from_millis(10));
+    }
+    child
+}
+
+fn terminate(child: &mut Child) -> ExitStatus {
+    kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM).unwrap();
+    child.wait().unwrap()
+}
+
+fn write_script(path: &Path, body: &str) {
+    std::fs::write(path, format!("#!/bin/sh\n{body}\n")).unwrap();
+    std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+}
+
+#[test]
+fn test_drained_shutdown_succeeds() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut child = start(dir.path(), "GET:/ echo hello");
+
+    let mut stream = UnixStream::connect(dir.path().join("http.sock")).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+
+    assert_eq!(terminate(&mut child).code(), Some(0));
+}
+
+#[test]
+fn test_drain_timeout_fails() {
+    let dir = tempfile::tempdir().unwrap();
+    let handler = dir.path().join("slow.sh");
+    let pid_file = dir.path().join("pid");
+    write_script(&handler, &format!("echo $$ > {}\nsleep 30", pid_file.display()));
+    let mut child = start(dir.path(), &format!("GET:/ {}", handler.display()));
+
+    let mut stream = UnixStream::connect(dir.path().join("http.sock")).unwrap();
+    stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    while !pid_file.exists() {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    // the handler outlives the drain timeout, and is terminated
+    let start = Instant::now();
+    assert_eq!(terminate(&mut child).code(), Some(1));
+    assert!(start.elapsed() >= Duration::from_secs(1));
+
+    // gone, or a zombie left for init to reap
+    let pid = std::fs::read_to_string(&pid_file).unwrap();
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.trim())).unwrap_or_default();
+    assert!(stat.rsplit(") ").next().is_none_or(|state| state.is_empty() || state.starts_with('Z')));
+}